@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counts the hits of very hot keys in memory, so that they only reach the db
+/// in periodic batches. Rates are approximated by the last known count in the
+/// db, plus the hits still pending locally.
+#[derive(Default)]
+pub struct Accumulator {
+    keys: Mutex<HashMap<String, Pending>>,
+}
+
+struct Pending {
+    hits: u64,
+    synced: i64,
+    interval: usize,
+    last_hit: Instant,
+}
+
+impl Accumulator {
+    /// Adds hits to a key, and returns its approximate rate
+    pub fn add(&self, key: &str, hits: u64, interval: usize) -> i64 {
+        let mut keys = self.keys.lock().unwrap();
+        let pending = keys.entry(key.to_owned()).or_insert_with(|| Pending {
+            hits: 0,
+            synced: 0,
+            interval,
+            last_hit: Instant::now(),
+        });
+        pending.hits += hits;
+        pending.last_hit = Instant::now();
+        pending.synced + pending.hits as i64
+    }
+
+    /// Takes the pending hits of every key, along with its interval.
+    /// Keys that were not hit for a whole interval are dropped, as their
+    /// window has ended.
+    pub fn drain(&self) -> Vec<(String, u64, usize)> {
+        let mut keys = self.keys.lock().unwrap();
+        let now = Instant::now();
+        keys.retain(|_, pending| {
+            pending.hits > 0
+                || now.duration_since(pending.last_hit)
+                    < Duration::from_secs(pending.interval as u64)
+        });
+        keys.iter_mut()
+            .map(|(key, pending)| {
+                (
+                    key.to_owned(),
+                    std::mem::take(&mut pending.hits),
+                    pending.interval,
+                )
+            })
+            .collect()
+    }
+
+    /// Records the count in the db of a key, after its hits were flushed
+    pub fn synced(&self, key: &str, rate: i64) {
+        if let Some(pending) = self.keys.lock().unwrap().get_mut(key) {
+            pending.synced = rate;
+        }
+    }
+
+    /// Puts back hits that could not be flushed to the db
+    pub fn restore(&self, key: &str, hits: u64) {
+        if let Some(pending) = self.keys.lock().unwrap().get_mut(key) {
+            pending.hits += hits;
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use opentelemetry::Context;
+use prometheus::TEXT_FORMAT;
+use tokio::sync::watch::Receiver;
+use tracing::{error, info};
+
+use crate::health::Health;
+use crate::json::{JsonRequest, JsonResponse};
+use crate::metrics::Metrics;
+use crate::service::{LoadedConfigs, RateLimitConfigs, Steward};
+
+/// What the admin endpoints report on
+pub struct Admin {
+    metrics: Arc<Metrics>,
+    health: Health,
+    configs: Receiver<LoadedConfigs>,
+    steward: Arc<Steward>,
+}
+
+impl Admin {
+    pub fn new(
+        metrics: Arc<Metrics>,
+        health: Health,
+        configs: Receiver<LoadedConfigs>,
+        steward: Arc<Steward>,
+    ) -> Self {
+        Self {
+            metrics,
+            health,
+            configs,
+            steward,
+        }
+    }
+}
+
+/// Serves the admin endpoints over plain HTTP, next to the gRPC server
+pub async fn serve(addr: SocketAddr, admin: Arc<Admin>) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let admin = admin.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, admin.clone()))) }
+    });
+    info!("Serving admin endpoints on {addr}");
+    Server::bind(&addr).serve(make_service).await
+}
+
+async fn handle(req: Request<Body>, admin: Arc<Admin>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, TEXT_FORMAT)
+            .body(Body::from(admin.metrics.render())),
+        (&Method::GET, "/healthz") => Response::builder().body(Body::from("OK")),
+        (&Method::GET, "/readyz") => match admin.health.is_serving() {
+            true => Response::builder().body(Body::from("OK")),
+            false => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from("NOT SERVING")),
+        },
+        (&Method::GET, "/config") => match serde_json::to_string(&*admin.configs.borrow()) {
+            Ok(json) => Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(json)),
+            Err(e) => {
+                error!("Failed to serialize rate limit configs: {e}");
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+            }
+        },
+        (&Method::GET, "/rlconfig") => Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from(dump_configs(&admin.configs.borrow().configs))),
+        (&Method::POST, "/json") => should_rate_limit(req, &admin.steward).await,
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+/// Decides on a JSON rate limit request the same way as the gRPC service does.
+/// Requests over the limit are answered with `429`, and invalid ones with `400`.
+async fn should_rate_limit(
+    req: Request<Body>,
+    steward: &Arc<Steward>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let request = match hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|body| serde_json::from_slice::<JsonRequest>(&body).map_err(|e| e.to_string()))
+    {
+        Ok(request) => request,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Invalid rate limit request: {e}")))
+        }
+    };
+    // Deciding calls the store, which blocks
+    let steward = steward.clone();
+    let decided =
+        tokio::task::spawn_blocking(move || steward.decide(request.into(), Context::new())).await;
+    let response = match decided {
+        Ok(Ok(decision)) => JsonResponse::from(decision),
+        Ok(Err(e)) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(e))
+        }
+        Err(e) => {
+            error!("Failed to decide on rate limit request: {e}");
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty());
+        }
+    };
+    let code = match response.overall_code {
+        "OVER_LIMIT" => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::OK,
+    };
+    match serde_json::to_string(&response) {
+        Ok(json) => Response::builder()
+            .status(code)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => {
+            error!("Failed to serialize rate limit response: {e}");
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+        }
+    }
+}
+
+/// Lists every descriptor with its rate limit, one per line, sorted by domain:
+/// `domain.key_value: unit=minutes requests_per_unit=10, shadow_mode: false`
+fn dump_configs(configs: &RateLimitConfigs) -> String {
+    let mut domains: Vec<_> = configs.iter().collect();
+    domains.sort_by_key(|(name, _)| name.as_str());
+    let mut dump = String::new();
+    for (name, domain) in domains {
+        for descriptor in domain.descriptors.iter() {
+            let _ = write!(dump, "{name}.{}_{}: ", descriptor.key, descriptor.value);
+            match &descriptor.rate_limit {
+                _ if descriptor.unlimited => dump.push_str("unlimited"),
+                Some(limit) => {
+                    let _ = write!(
+                        dump,
+                        "unit={} requests_per_unit={}",
+                        limit.unit, limit.requests_per_unit
+                    );
+                    if let Some(multiplier) = limit.unit_multiplier {
+                        let _ = write!(dump, " unit_multiplier={multiplier}");
+                    }
+                }
+                None => dump.push_str("no rate limit"),
+            }
+            let shadow_mode = domain.settings.shadow_mode || descriptor.shadow_mode;
+            let _ = writeln!(dump, ", shadow_mode: {shadow_mode}");
+        }
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::service::tests::steward;
+
+    fn post(body: Value) -> Request<Body> {
+        Request::post("/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn json_decisions_are_answered_with_their_status_code() {
+        let steward = Arc::new(steward(
+            json!({}),
+            json!({"domain": [{
+                "key": "a",
+                "value": "1",
+                "rate_limit": {"unit": "minutes", "requests_per_unit": 1},
+            }]}),
+        ));
+        let a =
+            json!({"domain": "domain", "descriptors": [{"entries": [{"key": "a", "value": "1"}]}]});
+        let status = |body| async {
+            should_rate_limit(post(body), &steward)
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(status(a.clone()).await, StatusCode::OK);
+        assert_eq!(status(a).await, StatusCode::TOO_MANY_REQUESTS);
+
+        let unknown_unit = json!({"domain": "domain", "descriptors": [{
+            "entries": [{"key": "a", "value": "1"}],
+            "limit": {"requests_per_unit": 1, "unit": "unknown"},
+        }]});
+        assert_eq!(status(unknown_unit).await, StatusCode::BAD_REQUEST);
+        let misspelled_unit = json!({"domain": "domain", "descriptors": [{
+            "entries": [{"key": "a", "value": "1"}],
+            "limit": {"requests_per_unit": 1, "unit": "MINUTE"},
+        }]});
+        assert_eq!(status(misspelled_unit).await, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status(json!({"domain": "domain"})).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Remembers counter keys that are over their limit until their window ends,
+/// so that requests for them can be answered without a round trip to the db
+pub struct OverLimitCache {
+    capacity: usize,
+    keys: Mutex<HashMap<String, Instant>>,
+}
+
+impl OverLimitCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            keys: Mutex::new(HashMap::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the time until the window of the key ends, if it is over its limit
+    pub fn get(&self, key: &str) -> Option<Duration> {
+        let mut keys = self.keys.lock().unwrap();
+        let now = Instant::now();
+        match keys.get(key) {
+            Some(until) if *until > now => Some(*until - now),
+            Some(_) => {
+                keys.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Adds a key that is over its limit for the given time.
+    /// When the cache is full, expired keys are dropped first, and then the
+    /// key whose window ends soonest.
+    pub fn insert(&self, key: &str, ttl: Duration) {
+        let mut keys = self.keys.lock().unwrap();
+        let now = Instant::now();
+        if keys.len() >= self.capacity && !keys.contains_key(key) {
+            keys.retain(|_, until| *until > now);
+            if keys.len() >= self.capacity {
+                let soonest = keys
+                    .iter()
+                    .min_by_key(|(_, until)| **until)
+                    .map(|(k, _)| k.to_owned());
+                if let Some(soonest) = soonest {
+                    keys.remove(&soonest);
+                }
+            }
+        }
+        keys.insert(key.to_owned(), now + ttl);
+    }
+}
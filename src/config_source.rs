@@ -1,7 +1,7 @@
 use config::{Config, ConfigError, Environment, File};
 use reqwest::Url;
 use serde::Deserialize;
-use std::{env, net::Ipv4Addr};
+use std::{collections::HashMap, env, net::Ipv4Addr};
 
 use crate::service::RateLimitConfigs;
 
@@ -17,7 +17,10 @@ pub async fn get_config() -> Result<RateLimitConfigs, String> {
 
 pub async fn get_http_config(url: Url) -> Result<RateLimitConfigs, String> {
     match reqwest::get(url).await {
-        Ok(response) => Ok(response.json().await.unwrap()),
+        Ok(response) => response
+            .json()
+            .await
+            .map_err(|e| format!("Invalid rate limit config: {e}")),
         Err(e) => Err(format!("{e:?}")),
     }
 }
@@ -29,19 +32,66 @@ pub enum ConfigSource {
     Http(String),
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    Redis,
+    Local,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ListenConfig {
     pub addr: Ipv4Addr,
     pub port: u16,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsdSettings {
+    pub host: String,
+    pub prefix: Option<String>,
+    pub tags: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TracingSettings {
+    pub otlp_endpoint: String,
+    pub service_name: Option<String>,
+    pub sample_ratio: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsSettings {
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: Option<String>,
+    pub client_san_patterns: Option<Vec<String>>,
+    pub reload_interval_ms: Option<u64>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub listen: ListenConfig,
+    pub tls: Option<TlsSettings>,
     pub rate_limit_configs: ConfigSource,
-    pub redis_host: String,
+    pub storage: Option<Storage>,
+    pub redis_host: Option<String>,
     pub redis_connections: Option<usize>,
+    pub redis_timeout_ms: Option<u64>,
+    pub key_prefix: Option<String>,
     pub default_ttl: usize,
+    pub aligned_windows: Option<bool>,
+    pub check_then_commit: Option<bool>,
+    pub zero_hits_addend_is_query: Option<bool>,
+    pub near_limit_ratio: Option<f64>,
+    pub local_cache_size: Option<usize>,
+    pub approximate_sync_interval_ms: Option<u64>,
+    pub statsd: Option<StatsdSettings>,
+    pub metrics_max_details: Option<usize>,
+    pub admin_listen: Option<ListenConfig>,
+    pub tracing: Option<TracingSettings>,
+    pub health_check_interval_ms: Option<u64>,
+    pub store_outage_threshold_ms: Option<u64>,
+    pub drain_period_ms: Option<u64>,
 }
 
 impl Settings {
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch::Receiver;
+use tokio::time::sleep;
+use tonic::transport::NamedService;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::{error, info, warn};
+
+use crate::proto::envoy::service::ratelimit::v3::rate_limit_service_server::RateLimitServiceServer;
+use crate::service::{LoadedConfigs, Steward};
+use crate::store::Store;
+
+/// The health of the rate limit service, as reported to gRPC health checks
+/// and the admin readiness endpoint
+#[derive(Clone)]
+pub struct Health {
+    reporter: HealthReporter,
+    serving: Arc<AtomicBool>,
+}
+
+impl Health {
+    /// Starts out as not serving
+    pub async fn new(reporter: HealthReporter) -> Self {
+        let mut health = Self {
+            reporter,
+            serving: Arc::default(),
+        };
+        health.set_serving(false).await;
+        health
+    }
+
+    pub fn is_serving(&self) -> bool {
+        self.serving.load(Ordering::Relaxed)
+    }
+
+    /// Sets the status of the rate limit service, and of the server as a whole
+    pub async fn set_serving(&mut self, serving: bool) {
+        self.serving.store(serving, Ordering::Relaxed);
+        let status = match serving {
+            true => ServingStatus::Serving,
+            false => ServingStatus::NotServing,
+        };
+        self.reporter.set_service_status("", status).await;
+        self.reporter
+            .set_service_status(
+                <RateLimitServiceServer<Arc<Steward>> as NamedService>::NAME,
+                status,
+            )
+            .await;
+    }
+}
+
+/// Reports the rate limit service as serving once the first config is loaded
+/// and the store is reachable. It goes back to not serving whenever the store
+/// has been unreachable for longer than the outage threshold.
+pub async fn report_health(
+    mut health: Health,
+    mut configs: Receiver<LoadedConfigs>,
+    store: Arc<dyn Store>,
+    interval: Duration,
+    outage_threshold: Duration,
+) {
+    if configs.changed().await.is_err() {
+        error!("Config source stopped before loading any config");
+        return;
+    }
+    info!("Loaded the first rate limit config");
+
+    let mut last_reachable: Option<Instant> = None;
+    loop {
+        let ping_store = store.clone();
+        match tokio::task::spawn_blocking(move || ping_store.ping()).await {
+            Ok(Ok(())) => last_reachable = Some(Instant::now()),
+            Ok(Err(e)) => warn!("Store is unreachable: {e}"),
+            Err(e) => error!("Failed to ping store: {e}"),
+        }
+        let healthy = last_reachable.is_some_and(|at| at.elapsed() <= outage_threshold);
+        if healthy != health.is_serving() {
+            match healthy {
+                true => info!("Rate limit service is serving"),
+                false => error!("Store has been unreachable for too long, not serving"),
+            }
+            health.set_serving(healthy).await;
+        }
+        sleep(interval).await;
+    }
+}
@@ -0,0 +1,268 @@
+use std::num::NonZeroU32;
+
+use prost_types::value::Kind;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::{
+    Entry, RateLimitOverride,
+};
+use crate::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+use crate::proto::envoy::service::ratelimit::v3::rate_limit_response::Code;
+use crate::proto::envoy::service::ratelimit::v3::RateLimitRequest;
+use crate::rate_limits::Unit;
+use crate::service::{DecidedStatus, Decision};
+
+/// A `RateLimitRequest` for clients that cannot speak gRPC
+#[derive(Deserialize)]
+pub struct JsonRequest {
+    pub domain: String,
+    pub descriptors: Vec<JsonDescriptor>,
+    #[serde(default)]
+    pub hits_addend: u32,
+}
+
+#[derive(Deserialize)]
+pub struct JsonDescriptor {
+    pub entries: Vec<JsonEntry>,
+    #[serde(default)]
+    pub limit: Option<JsonLimit>,
+    #[serde(default)]
+    pub hits_addend: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct JsonEntry {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JsonLimit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub requests_per_unit: u32,
+    pub unit: Unit,
+    /// Only reported, as overrides cannot stretch their window
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub unit_multiplier: Option<NonZeroU32>,
+}
+
+impl From<JsonRequest> for RateLimitRequest {
+    fn from(value: JsonRequest) -> Self {
+        Self {
+            domain: value.domain,
+            descriptors: value
+                .descriptors
+                .into_iter()
+                .map(|descriptor| RateLimitDescriptor {
+                    entries: descriptor
+                        .entries
+                        .into_iter()
+                        .map(|entry| Entry {
+                            key: entry.key,
+                            value: entry.value,
+                        })
+                        .collect(),
+                    limit: descriptor.limit.map(|limit| RateLimitOverride {
+                        requests_per_unit: limit.requests_per_unit,
+                        unit: limit.unit as i32,
+                    }),
+                    hits_addend: descriptor.hits_addend,
+                })
+                .collect(),
+            hits_addend: value.hits_addend,
+        }
+    }
+}
+
+/// The decision on a `JsonRequest`, with the same statuses, headers and
+/// metadata as the gRPC response
+#[derive(Serialize)]
+pub struct JsonResponse {
+    pub overall_code: &'static str,
+    pub statuses: Vec<JsonStatus>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub response_headers_to_add: Vec<JsonHeader>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dynamic_metadata: Option<Value>,
+}
+
+#[derive(Serialize)]
+pub struct JsonStatus {
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_limit: Option<JsonLimit>,
+    pub limit_remaining: u32,
+    /// Seconds until the limit resets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_until_reset: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct JsonHeader {
+    pub key: String,
+    pub value: String,
+}
+
+impl From<Decision> for JsonResponse {
+    fn from(value: Decision) -> Self {
+        Self {
+            overall_code: match value.over() {
+                true => code_name(Code::OverLimit),
+                false => code_name(Code::Ok),
+            },
+            statuses: value.statuses.into_iter().map(JsonStatus::from).collect(),
+            response_headers_to_add: value
+                .headers
+                .into_iter()
+                .map(|header| JsonHeader {
+                    key: header.key,
+                    value: header.value,
+                })
+                .collect(),
+            dynamic_metadata: value
+                .metadata
+                .map(|metadata| json_value(Kind::StructValue(metadata))),
+        }
+    }
+}
+
+impl From<DecidedStatus> for JsonStatus {
+    fn from(value: DecidedStatus) -> Self {
+        let status = value.status;
+        Self {
+            code: code_name(status.code()),
+            current_limit: status.current_limit.map(|limit| JsonLimit {
+                name: Some(limit.name).filter(|name| !name.is_empty()),
+                requests_per_unit: limit.requests_per_unit,
+                unit: Unit::from(limit.unit),
+                unit_multiplier: value.unit_multiplier,
+            }),
+            limit_remaining: status.limit_remaining,
+            duration_until_reset: status.duration_until_reset.map(|d| d.seconds),
+        }
+    }
+}
+
+fn code_name(code: Code) -> &'static str {
+    match code {
+        Code::Unknown => "UNKNOWN",
+        Code::Ok => "OK",
+        Code::OverLimit => "OVER_LIMIT",
+    }
+}
+
+fn json_value(kind: Kind) -> Value {
+    match kind {
+        Kind::NullValue(_) => Value::Null,
+        Kind::NumberValue(number) => number.into(),
+        Kind::StringValue(string) => string.into(),
+        Kind::BoolValue(boolean) => boolean.into(),
+        Kind::StructValue(fields) => Value::Object(
+            fields
+                .fields
+                .into_iter()
+                .map(|(key, value)| (key, value.kind.map(json_value).unwrap_or_default()))
+                .collect(),
+        ),
+        Kind::ListValue(list) => Value::Array(
+            list.values
+                .into_iter()
+                .map(|value| value.kind.map(json_value).unwrap_or_default())
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::rate_limits::RateLimit;
+    use crate::response::{descriptor_status, near_limit_header, near_limit_metadata};
+
+    #[test]
+    fn json_requests_become_rate_limit_requests() {
+        let request: JsonRequest = serde_json::from_value(json!({
+            "domain": "domain",
+            "descriptors": [
+                {
+                    "entries": [{"key": "a", "value": "1"}, {"key": "b", "value": "2"}],
+                    "limit": {"requests_per_unit": 5, "unit": "hours", "unit_multiplier": 3},
+                    "hits_addend": 2,
+                },
+                {"entries": [{"key": "c", "value": "3"}]},
+            ],
+        }))
+        .unwrap();
+        let request = RateLimitRequest::from(request);
+        assert_eq!(request.domain, "domain");
+        assert_eq!(request.hits_addend, 0);
+        let [overridden, plain] = &request.descriptors[..] else {
+            panic!("expected two descriptors");
+        };
+        let entries: Vec<_> = overridden
+            .entries
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.value.as_str()))
+            .collect();
+        assert_eq!(entries, vec![("a", "1"), ("b", "2")]);
+        // Overrides cannot stretch their window, so the multiplier is ignored
+        assert_eq!(
+            overridden.limit,
+            Some(RateLimitOverride {
+                requests_per_unit: 5,
+                unit: Unit::Hours as i32,
+            })
+        );
+        assert_eq!(overridden.hits_addend, Some(2));
+        assert_eq!(plain.limit, None);
+        assert_eq!(plain.hits_addend, None);
+    }
+
+    #[test]
+    fn decisions_become_json_responses() {
+        let limit: RateLimit = serde_json::from_value(
+            json!({"unit": "minutes", "requests_per_unit": 10, "unit_multiplier": 5}),
+        )
+        .unwrap();
+        let decision = Decision {
+            statuses: vec![
+                DecidedStatus {
+                    status: descriptor_status(true, Some(&limit), Some("per_user"), -1, Some(42)),
+                    unit_multiplier: limit.unit_multiplier,
+                },
+                DecidedStatus {
+                    status: descriptor_status(false, None, None, 0, None),
+                    unit_multiplier: None,
+                },
+            ],
+            headers: vec![near_limit_header()],
+            metadata: Some(near_limit_metadata(&["per_path".to_owned()])),
+        };
+        assert_eq!(
+            serde_json::to_value(JsonResponse::from(decision)).unwrap(),
+            json!({
+                "overall_code": "OVER_LIMIT",
+                "statuses": [
+                    {
+                        "code": "OVER_LIMIT",
+                        "current_limit": {
+                            "name": "per_user",
+                            "requests_per_unit": 10,
+                            "unit": "minutes",
+                            "unit_multiplier": 5,
+                        },
+                        "limit_remaining": 0,
+                        "duration_until_reset": 42,
+                    },
+                    {"code": "OK", "limit_remaining": 0},
+                ],
+                "response_headers_to_add": [{"key": "X-RateLimit-Near-Limit", "value": "true"}],
+                "dynamic_metadata": {"near_limit": true, "near_limit_rules": ["per_path"]},
+            })
+        );
+    }
+}
@@ -1,5 +1,14 @@
+pub mod accumulator;
+pub mod admin;
+pub mod cache;
 pub mod config_source;
+pub mod health;
+pub mod json;
+pub mod metrics;
 pub mod proto;
 pub mod rate_limits;
 pub mod response;
 pub mod service;
+pub mod store;
+pub mod telemetry;
+pub mod tls;
@@ -1,36 +1,32 @@
 use socket2::{Domain, Socket, Type};
-use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::watch;
 use tokio::time::{sleep, Duration};
 use tokio_stream::wrappers::TcpListenerStream;
 use tonic::transport::Server;
 
-use tracing::error;
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
+use tracing::{error, info, info_span, warn, Instrument};
 
+use steward::admin::{self, Admin};
 use steward::config_source::{get_http_config, ConfigSource, Settings};
+use steward::health::{self, Health};
+use steward::metrics::Metrics;
 use steward::proto::envoy::service::ratelimit::v3::rate_limit_service_server::RateLimitServiceServer;
-use steward::service::Steward;
+use steward::service::{LoadedConfigs, Steward};
+use steward::{telemetry, tls};
 
 const MINUTE: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    FmtSubscriber::builder()
-        .with_env_filter(EnvFilter::from_default_env())
-        .event_format(
-            tracing_subscriber::fmt::format()
-                .with_file(true)
-                .with_line_number(true),
-        )
-        .compact()
-        .json()
-        .init();
+    let settings = Settings::new();
+    telemetry::init(settings.as_ref().ok().and_then(|s| s.tracing.as_ref()));
 
-    let settings = match Settings::new() {
+    let settings = match settings {
         Ok(s) => s,
         Err(e) => {
             error!("Could not load config: {e}");
@@ -38,36 +34,89 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    let (tx, rx) = watch::channel(HashMap::new());
+    let metrics = match Metrics::new(settings.statsd.as_ref(), settings.metrics_max_details) {
+        Ok(m) => Arc::new(m),
+        Err(e) => {
+            error!("Could not set up metrics: {e}");
+            panic!()
+        }
+    };
 
-    tokio::spawn(async move {
+    let (tx, rx) = watch::channel(LoadedConfigs::default());
+    let (health_rx, admin_rx) = (rx.clone(), rx.clone());
+
+    let config_source = settings.rate_limit_configs.clone();
+    let reload_metrics = metrics.clone();
+    let refresher = tokio::spawn(async move {
         loop {
-            match settings.rate_limit_configs.clone() {
+            match config_source.clone() {
                 ConfigSource::File(_) => {
-                    let _ = tx.send(HashMap::new());
+                    let _ = tx.send(LoadedConfigs::default());
                     todo!()
                 }
                 ConfigSource::Http(url) => {
                     let u = url.as_str().try_into().unwrap();
-                    match get_http_config(u).await {
+                    let span = info_span!("config_reload", source = %url);
+                    match get_http_config(u).instrument(span).await {
                         Ok(conf) => {
-                            let _ = tx.send(conf);
+                            if tx.send_if_modified(|loaded| loaded.update(conf, &url)) {
+                                info!(
+                                    "Loaded version {} of the rate limit config",
+                                    tx.borrow().version
+                                );
+                            }
+                            reload_metrics.config_loaded(&tx.borrow());
+                        }
+                        Err(e) => {
+                            reload_metrics.config_reload_failed();
+                            panic!("Failed to get config from http: {e}")
                         }
-                        Err(e) => panic!("Failed to get config from http: {e}"),
                     }
                 }
             }
-            // TODO: healthcheck to indicate that the server is ready
             sleep(MINUTE).await;
         }
     });
 
-    let steward = Steward::new(
-        settings.redis_host.as_str(),
-        settings.default_ttl,
-        rx,
-        settings.redis_connections.unwrap_or(1),
-    );
+    let steward = match Steward::new(&settings, rx, metrics.clone()) {
+        Ok(s) => Arc::new(s),
+        Err(e) => {
+            error!("Could not set up rate limit storage: {e}");
+            panic!()
+        }
+    };
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    let mut health = Health::new(health_reporter).await;
+    let health_check = tokio::spawn(health::report_health(
+        health.clone(),
+        health_rx,
+        steward.store(),
+        Duration::from_millis(settings.health_check_interval_ms.unwrap_or(1000)),
+        Duration::from_millis(settings.store_outage_threshold_ms.unwrap_or(5000)),
+    ));
+
+    if let Some(listen) = &settings.admin_listen {
+        let client_auth = settings
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.client_ca_path.is_some());
+        if client_auth && !listen.addr.is_loopback() {
+            warn!("admin_listen is not a loopback address, so clients without a certificate can rate limit through POST /json");
+        }
+        let addr = SocketAddr::new(std::net::IpAddr::V4(listen.addr), listen.port);
+        let admin = Arc::new(Admin::new(
+            metrics,
+            health.clone(),
+            admin_rx,
+            steward.clone(),
+        ));
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(addr, admin).await {
+                error!("Admin server failed: {e}");
+            }
+        });
+    }
 
     // gRPC server setup
     let addr = SocketAddr::new(
@@ -81,14 +130,61 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     socket.set_nonblocking(true)?;
     socket.listen(128)?; // backlog
     let async_listener = TcpListener::from_std(std::net::TcpListener::from(socket))?;
-    let incoming = TcpListenerStream::new(async_listener);
-    let service = RateLimitServiceServer::new(steward);
-    Server::builder()
+    let service = RateLimitServiceServer::new(steward.clone());
+    let drain_period = Duration::from_millis(settings.drain_period_ms.unwrap_or(5000));
+    let shutdown = async move {
+        shutdown_signal().await;
+        info!("Shutting down, draining connections for {drain_period:?}");
+        refresher.abort();
+        health_check.abort();
+        health.set_serving(false).await;
+        sleep(drain_period).await;
+    };
+    let server = Server::builder()
         .tcp_keepalive(Some(MINUTE))
         .http2_keepalive_interval(Some(MINUTE))
         .http2_keepalive_timeout(Some(MINUTE))
-        .add_service(service)
-        .serve_with_incoming(incoming)
-        .await?;
+        .add_service(health_service)
+        .add_service(service);
+    match settings.tls {
+        Some(tls_settings) => {
+            let incoming = match tls::incoming(async_listener, tls_settings) {
+                Ok(incoming) => incoming,
+                Err(e) => {
+                    error!("Could not set up TLS: {e}");
+                    panic!()
+                }
+            };
+            server
+                .serve_with_incoming_shutdown(incoming, shutdown)
+                .await?
+        }
+        None => {
+            let incoming = TcpListenerStream::new(async_listener);
+            server
+                .serve_with_incoming_shutdown(incoming, shutdown)
+                .await?
+        }
+    }
+    info!("Server stopped");
+    if let Err(e) = tokio::task::spawn_blocking(move || steward.flush()).await {
+        error!("Failed to flush approximate counters: {e}");
+    }
+    telemetry::shutdown();
     Ok(())
 }
+
+/// Waits for either SIGTERM or SIGINT
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Could not listen for SIGTERM: {e}");
+            panic!()
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::{HashMap, HashSet};
+use std::net::UdpSocket;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use cadence::prelude::*;
+use cadence::{NopMetricSink, QueuingMetricSink, StatsdClient, UdpMetricSink};
+use prometheus::core::Collector;
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, HistogramTimer, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tracing::{error, warn};
+
+use crate::config_source::StatsdSettings;
+use crate::service::LoadedConfigs;
+
+const DEFAULT_MAX_DETAILS: usize = 1000;
+
+/// Reports what the service is doing to StatsD, when it is configured,
+/// and keeps the same metrics for Prometheus to scrape
+pub struct Metrics {
+    statsd: StatsdClient,
+    prometheus: Prometheus,
+    max_details: usize,
+    /// The detailed values reported so far, for each rule with detailed metrics
+    details: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+struct Prometheus {
+    registry: Registry,
+    requests: IntCounterVec,
+    hits: IntCounterVec,
+    decisions: IntCounterVec,
+    request_duration: Histogram,
+    store_duration: Histogram,
+    store_errors: IntCounter,
+    pool_connections: IntGaugeVec,
+    config_reloads: IntCounterVec,
+    config_loaded_at: IntGauge,
+    config_domains: IntGauge,
+    config_version: IntGauge,
+}
+
+impl Prometheus {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("steward".to_owned()), None).unwrap();
+        Self {
+            requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("requests_total", "Rate limit requests"),
+                    &["domain"],
+                ),
+            ),
+            hits: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("rule_hits_total", "Hits added to each rule"),
+                    &["domain", "rule", "detail"],
+                ),
+            ),
+            decisions: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "rule_decisions_total",
+                        "Requests that were over, near, or over in shadow mode, each rule",
+                    ),
+                    &["domain", "rule", "detail", "decision"],
+                ),
+            ),
+            request_duration: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "should_rate_limit_duration_seconds",
+                        "Time taken to decide on a rate limit request",
+                    )
+                    .buckets(latency_buckets()),
+                ),
+            ),
+            store_duration: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "store_call_duration_seconds",
+                        "Time taken by calls to the counter store",
+                    )
+                    .buckets(latency_buckets()),
+                ),
+            ),
+            store_errors: register(
+                &registry,
+                IntCounter::new("store_errors_total", "Failed calls to the counter store"),
+            ),
+            pool_connections: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("store_pool_connections", "Connections of the store pool"),
+                    &["state"],
+                ),
+            ),
+            config_reloads: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("config_reloads_total", "Rate limit config refreshes"),
+                    &["result"],
+                ),
+            ),
+            config_loaded_at: register(
+                &registry,
+                IntGauge::new(
+                    "config_last_load_timestamp_seconds",
+                    "When the rate limit config was last loaded",
+                ),
+            ),
+            config_domains: register(
+                &registry,
+                IntGauge::new("config_domains", "Domains in the rate limit config"),
+            ),
+            config_version: register(
+                &registry,
+                IntGauge::new(
+                    "config_version",
+                    "Version of the rate limit config, which goes up whenever it changes",
+                ),
+            ),
+            registry,
+        }
+    }
+}
+
+/// From 100µs up to a few seconds, as most calls take well under a millisecond
+fn latency_buckets() -> Vec<f64> {
+    exponential_buckets(0.0001, 2.0, 16).unwrap()
+}
+
+fn register<T: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: prometheus::Result<T>,
+) -> T {
+    let metric = metric.unwrap();
+    registry.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+impl Metrics {
+    pub fn new(
+        settings: Option<&StatsdSettings>,
+        max_details: Option<usize>,
+    ) -> Result<Self, String> {
+        let max_details = max_details.unwrap_or(DEFAULT_MAX_DETAILS);
+        let settings = match settings {
+            Some(settings) => settings,
+            None => return Ok(Self::with_statsd(Self::nop_statsd(), max_details)),
+        };
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .map_err(|e| format!("Failed to bind statsd socket: {e}"))?;
+        socket
+            .set_nonblocking(true)
+            .map_err(|e| format!("Failed to set up statsd socket: {e}"))?;
+        let sink = UdpMetricSink::from(settings.host.as_str(), socket)
+            .map_err(|e| format!("Invalid statsd host {}: {e}", settings.host))?;
+        let prefix = settings.prefix.as_deref().unwrap_or("steward");
+        let mut builder = StatsdClient::builder(prefix, QueuingMetricSink::from(sink))
+            .with_error_handler(|e| warn!("Failed to send metric: {e}"));
+        for (key, value) in settings.tags.iter().flatten() {
+            builder = builder.with_tag(key, value);
+        }
+        Ok(Self::with_statsd(builder.build(), max_details))
+    }
+
+    fn with_statsd(statsd: StatsdClient, max_details: usize) -> Self {
+        Self {
+            statsd,
+            prometheus: Prometheus::new(),
+            max_details,
+            details: Mutex::default(),
+        }
+    }
+
+    fn nop_statsd() -> StatsdClient {
+        StatsdClient::builder("", NopMetricSink).build()
+    }
+
+    /// Returns the detailed value to report for a rule with detailed metrics.
+    /// The number of detailed values is capped for each rule, so that they
+    /// cannot grow the number of series without bound; values past the cap
+    /// are reported without one, rolled up into the rule.
+    fn detail<'a>(&self, rule: &str, detail: Option<&'a str>) -> Option<&'a str> {
+        let detail = detail?;
+        let mut details = self.details.lock().unwrap();
+        if !details.contains_key(rule) {
+            details.insert(rule.to_owned(), HashSet::new());
+        }
+        let values = details.get_mut(rule).unwrap();
+        if values.contains(detail) {
+            return Some(detail);
+        }
+        if values.len() >= self.max_details {
+            return None;
+        }
+        values.insert(detail.to_owned());
+        if values.len() == self.max_details {
+            warn!(
+                "Reached {} detailed values of rule {rule} in metrics, new values are reported without one",
+                self.max_details
+            );
+        }
+        Some(detail)
+    }
+
+    pub fn request(&self, domain: &str) {
+        self.statsd
+            .incr_with_tags("ratelimit.requests")
+            .with_tag("domain", domain)
+            .send();
+        self.prometheus.requests.with_label_values(&[domain]).inc();
+    }
+
+    /// Times a rate limit request until the returned timer is dropped
+    pub fn time_request(&self) -> HistogramTimer {
+        self.prometheus.request_duration.start_timer()
+    }
+
+    /// Counts the hits that a request adds to a rule
+    pub fn hits(&self, domain: &str, rule: &str, detail: Option<&str>, hits: u64) {
+        let detail = self.detail(rule, detail);
+        let mut metric = self
+            .statsd
+            .count_with_tags("ratelimit.hits", hits as i64)
+            .with_tag("domain", domain)
+            .with_tag("rule", rule);
+        if let Some(detail) = detail {
+            metric = metric.with_tag("detail", detail);
+        }
+        metric.send();
+        self.prometheus
+            .hits
+            .with_label_values(&[domain, rule, detail.unwrap_or_default()])
+            .inc_by(hits);
+    }
+
+    pub fn over_limit(&self, domain: &str, rule: &str, detail: Option<&str>) {
+        self.decision("over_limit", domain, rule, detail);
+    }
+
+    pub fn near_limit(&self, domain: &str, rule: &str, detail: Option<&str>) {
+        self.decision("near_limit", domain, rule, detail);
+    }
+
+    /// Counts requests that are over a limit, but allowed by shadow mode
+    pub fn shadow_mode(&self, domain: &str, rule: &str, detail: Option<&str>) {
+        self.decision("shadow_mode", domain, rule, detail);
+    }
+
+    /// Counts a decision on a rule, as `ratelimit.<decision>` in StatsD
+    fn decision(&self, decision: &str, domain: &str, rule: &str, detail: Option<&str>) {
+        let detail = self.detail(rule, detail);
+        let name = format!("ratelimit.{decision}");
+        let mut metric = self
+            .statsd
+            .incr_with_tags(&name)
+            .with_tag("domain", domain)
+            .with_tag("rule", rule);
+        if let Some(detail) = detail {
+            metric = metric.with_tag("detail", detail);
+        }
+        metric.send();
+        self.prometheus
+            .decisions
+            .with_label_values(&[domain, rule, detail.unwrap_or_default(), decision])
+            .inc();
+    }
+
+    pub fn store_call(&self, elapsed: Duration, ok: bool) {
+        self.statsd.time_with_tags("store.latency", elapsed).send();
+        self.prometheus
+            .store_duration
+            .observe(elapsed.as_secs_f64());
+        if !ok {
+            self.statsd.incr_with_tags("store.errors").send();
+            self.prometheus.store_errors.inc();
+        }
+    }
+
+    /// Reports the connections of the store pool, out of its maximum size
+    pub fn pool_state(&self, connections: u32, idle: u32, max: u32) {
+        let gauge = &self.prometheus.pool_connections;
+        gauge.with_label_values(&["idle"]).set(idle as i64);
+        gauge
+            .with_label_values(&["in_use"])
+            .set(connections.saturating_sub(idle) as i64);
+        gauge.with_label_values(&["max"]).set(max as i64);
+    }
+
+    pub fn config_loaded(&self, loaded: &LoadedConfigs) {
+        self.statsd.incr_with_tags("config.reloads").send();
+        let prometheus = &self.prometheus;
+        prometheus
+            .config_reloads
+            .with_label_values(&["success"])
+            .inc();
+        prometheus.config_version.set(loaded.version as i64);
+        prometheus.config_loaded_at.set(loaded.loaded_at as i64);
+        prometheus.config_domains.set(loaded.configs.len() as i64);
+    }
+
+    pub fn config_reload_failed(&self) {
+        self.statsd.incr_with_tags("config.reload_errors").send();
+        self.prometheus
+            .config_reloads
+            .with_label_values(&["failure"])
+            .inc();
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        match TextEncoder::new().encode_to_string(&self.prometheus.registry.gather()) {
+            Ok(text) => text,
+            Err(e) => {
+                error!("Failed to encode metrics: {e}");
+                String::new()
+            }
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::with_statsd(Self::nop_statsd(), DEFAULT_MAX_DETAILS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_detailed_values_of_each_rule() {
+        let metrics = Metrics::with_statsd(Metrics::nop_statsd(), 2);
+        assert_eq!(metrics.detail("users", Some("user_1")), Some("user_1"));
+        assert_eq!(metrics.detail("users", Some("user_2")), Some("user_2"));
+        assert_eq!(metrics.detail("users", Some("user_3")), None);
+        assert_eq!(metrics.detail("users", Some("user_1")), Some("user_1"));
+        assert_eq!(metrics.detail("paths", Some("path_/")), Some("path_/"));
+        for rule in ["login", "signup", "search"] {
+            assert_eq!(metrics.detail(rule, None), None);
+        }
+    }
+
+    #[test]
+    fn reports_details_next_to_their_rule() {
+        let metrics = Metrics::default();
+        metrics.over_limit("domain", "per_minute", Some("user_id_1"));
+        metrics.over_limit("domain", "per_hour", Some("user_id_1"));
+        metrics.over_limit("domain", "login", None);
+        let rendered = metrics.render();
+        for labels in [
+            r#"decision="over_limit",detail="user_id_1",domain="domain",rule="per_minute""#,
+            r#"decision="over_limit",detail="user_id_1",domain="domain",rule="per_hour""#,
+            r#"decision="over_limit",detail="",domain="domain",rule="login""#,
+        ] {
+            let series = format!("steward_rule_decisions_total{{{labels}}} 1");
+            assert!(rendered.contains(&series), "{series}");
+        }
+    }
+}
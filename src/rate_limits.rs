@@ -1,17 +1,131 @@
 use crate::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::RateLimitOverride;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::num::NonZeroU32;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "DomainConfig")]
+pub struct Domain {
+    pub descriptors: Vec<Descriptor>,
+    #[serde(flatten)]
+    pub settings: DomainSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct DomainSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_headers: Option<RateLimitHeaders>,
+    #[serde(default)]
+    pub shadow_mode: bool,
+    /// Ignores the rate limit overrides that Envoy sends along with descriptors
+    #[serde(default)]
+    pub disallow_overrides: bool,
+    /// Part of every counter key of the domain, so that bumping it starts
+    /// every counter of the domain over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report_near_limit: Option<NearLimitReport>,
+}
+
+/// A domain is either a plain list of descriptors, or an object holding
+/// the descriptors alongside settings that apply to the whole domain
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DomainConfig {
+    Descriptors(Vec<Descriptor>),
+    Domain {
+        descriptors: Vec<Descriptor>,
+        #[serde(flatten)]
+        settings: DomainSettings,
+    },
+}
+
+impl From<DomainConfig> for Domain {
+    fn from(value: DomainConfig) -> Self {
+        match value {
+            DomainConfig::Descriptors(descriptors) => Self {
+                descriptors,
+                settings: DomainSettings::default(),
+            },
+            DomainConfig::Domain {
+                descriptors,
+                settings,
+            } => Self {
+                descriptors,
+                settings,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitHeaders {
+    /// `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`
+    #[serde(rename = "x-ratelimit")]
+    XRateLimit,
+    /// `RateLimit-Policy` and `RateLimit`, as per the IETF httpapi draft
+    Ietf,
+}
+
+/// How to tell that a request is near the limit of any of its rules
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum NearLimitReport {
+    /// An `X-RateLimit-Near-Limit: true` response header
+    Header,
+    /// `near_limit` and `near_limit_rules` in the dynamic metadata
+    Metadata,
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Descriptor {
     pub key: String,
     pub value: String,
-    pub rate_limit: RateLimit,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Names of other descriptors that this one overrides when both match a request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replaces: Vec<Replaces>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+    /// Exempts matching request descriptors from every rate limit
+    #[serde(default)]
+    pub unlimited: bool,
+    /// Counts hits in memory and syncs them to the db periodically
+    #[serde(default)]
+    pub approximate: bool,
+    /// Counts requests as usual, but never rejects them
+    #[serde(default)]
+    pub shadow_mode: bool,
+    /// Reports metrics for every value of matching request descriptors,
+    /// rather than rolling them up into the rule
+    #[serde(default)]
+    pub detailed_metric: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Replaces {
+    pub name: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RateLimit {
     pub unit: Unit,
     pub requests_per_unit: i64,
+    /// Stretches the window to this many units. Configs with 0 are rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit_multiplier: Option<NonZeroU32>,
+}
+
+impl RateLimit {
+    /// Length of the rate limit window in seconds, e.g. 300 for
+    /// `unit: minutes` with `unit_multiplier: 5`
+    pub fn interval(&self) -> Result<usize, String> {
+        let multiplier = self.unit_multiplier.map_or(1, NonZeroU32::get) as usize;
+        Ok(usize::try_from(self.unit.clone())? * multiplier)
+    }
 }
 
 impl From<&RateLimitOverride> for RateLimit {
@@ -19,6 +133,7 @@ impl From<&RateLimitOverride> for RateLimit {
         Self {
             requests_per_unit: value.requests_per_unit as i64,
             unit: Unit::from(value.unit),
+            unit_multiplier: None,
         }
     }
 }
@@ -35,6 +150,21 @@ pub enum Unit {
     Years,
 }
 
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = match self {
+            Unit::Unknown => "unknown",
+            Unit::Seconds => "seconds",
+            Unit::Minutes => "minutes",
+            Unit::Hours => "hours",
+            Unit::Days => "days",
+            Unit::Months => "months",
+            Unit::Years => "years",
+        };
+        f.write_str(unit)
+    }
+}
+
 impl From<i32> for Unit {
     fn from(value: i32) -> Self {
         match value {
@@ -49,16 +179,18 @@ impl From<i32> for Unit {
     }
 }
 
-impl From<Unit> for usize {
-    fn from(value: Unit) -> Self {
+impl TryFrom<Unit> for usize {
+    type Error = String;
+
+    fn try_from(value: Unit) -> Result<Self, Self::Error> {
         match value {
-            Unit::Unknown => panic!(),
-            Unit::Seconds => 1,
-            Unit::Minutes => 60,
-            Unit::Hours => 3600,
-            Unit::Days => 86400,
-            Unit::Months => 2592000,
-            Unit::Years => 31536000,
+            Unit::Unknown => Err("unknown rate limit unit".to_string()),
+            Unit::Seconds => Ok(1),
+            Unit::Minutes => Ok(60),
+            Unit::Hours => Ok(3600),
+            Unit::Days => Ok(86400),
+            Unit::Months => Ok(2592000),
+            Unit::Years => Ok(31536000),
         }
     }
 }
@@ -1,18 +1,133 @@
-use crate::proto::envoy::service::ratelimit::v3::rate_limit_response::Code;
+use crate::proto::envoy::config::core::v3::HeaderValue;
+use crate::proto::envoy::service::ratelimit::v3::rate_limit_response::{
+    Code, DescriptorStatus, RateLimit as CurrentLimit,
+};
 use crate::proto::envoy::service::ratelimit::v3::RateLimitResponse;
+use crate::rate_limits::{RateLimit, RateLimitHeaders};
+use prost_types::{value::Kind, ListValue, Struct, Value};
 
-pub fn limit_response(over: bool) -> RateLimitResponse {
-    let code = match over {
-        true => Code::OverLimit,
-        false => Code::Ok,
-    };
+pub fn limit_response(
+    over: bool,
+    statuses: Vec<DescriptorStatus>,
+    headers: Vec<HeaderValue>,
+    dynamic_metadata: Option<Struct>,
+) -> RateLimitResponse {
     RateLimitResponse {
-        overall_code: code.into(),
+        overall_code: limit_code(over).into(),
         raw_body: vec![],
         request_headers_to_add: vec![],
-        response_headers_to_add: vec![],
-        dynamic_metadata: None,
+        response_headers_to_add: headers,
+        dynamic_metadata,
+        quota: None,
+        statuses,
+    }
+}
+
+pub fn descriptor_status(
+    over: bool,
+    limit: Option<&RateLimit>,
+    name: Option<&str>,
+    remaining: i64,
+    reset: Option<usize>,
+) -> DescriptorStatus {
+    DescriptorStatus {
+        code: limit_code(over).into(),
+        current_limit: limit.map(|l| CurrentLimit {
+            name: name.unwrap_or_default().to_string(),
+            requests_per_unit: l.requests_per_unit.clamp(0, u32::MAX as i64) as u32,
+            unit: l.unit.clone() as i32,
+        }),
+        limit_remaining: remaining.clamp(0, u32::MAX as i64) as u32,
+        duration_until_reset: reset.map(|seconds| prost_types::Duration {
+            seconds: seconds as i64,
+            nanos: 0,
+        }),
         quota: None,
-        statuses: vec![],
+    }
+}
+
+pub fn rate_limit_headers(
+    format: &RateLimitHeaders,
+    limit: &RateLimit,
+    window: usize,
+    remaining: u32,
+    reset: Option<usize>,
+) -> Vec<HeaderValue> {
+    let requests_per_unit = limit.requests_per_unit;
+    match format {
+        RateLimitHeaders::XRateLimit => {
+            let mut headers = vec![
+                header(
+                    "X-RateLimit-Limit",
+                    format!("{requests_per_unit}, {requests_per_unit};w={window}"),
+                ),
+                header("X-RateLimit-Remaining", remaining.to_string()),
+            ];
+            if let Some(reset) = reset {
+                headers.push(header("X-RateLimit-Reset", reset.to_string()));
+            }
+            headers
+        }
+        RateLimitHeaders::Ietf => {
+            let mut value = format!("limit={requests_per_unit}, remaining={remaining}");
+            if let Some(reset) = reset {
+                value.push_str(&format!(", reset={reset}"));
+            }
+            vec![
+                header(
+                    "RateLimit-Policy",
+                    format!("{requests_per_unit};w={window}"),
+                ),
+                header("RateLimit", value),
+            ]
+        }
+    }
+}
+
+/// Tells the client that the request is close to being rate limited
+pub fn near_limit_header() -> HeaderValue {
+    header("X-RateLimit-Near-Limit", "true".to_string())
+}
+
+/// Lists the rules that the request is near the limit of, for Envoy filters
+/// and access logs to pick up
+pub fn near_limit_metadata(rules: &[String]) -> Struct {
+    let rules = rules
+        .iter()
+        .map(|rule| Value {
+            kind: Some(Kind::StringValue(rule.to_owned())),
+        })
+        .collect();
+    Struct {
+        fields: [
+            (
+                "near_limit".to_string(),
+                Value {
+                    kind: Some(Kind::BoolValue(true)),
+                },
+            ),
+            (
+                "near_limit_rules".to_string(),
+                Value {
+                    kind: Some(Kind::ListValue(ListValue { values: rules })),
+                },
+            ),
+        ]
+        .into(),
+    }
+}
+
+fn header(key: &str, value: String) -> HeaderValue {
+    HeaderValue {
+        key: key.to_string(),
+        value,
+        ..Default::default()
+    }
+}
+
+fn limit_code(over: bool) -> Code {
+    match over {
+        true => Code::OverLimit,
+        false => Code::Ok,
     }
 }
@@ -1,98 +1,538 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crossbeam::deque::{Injector, Steal};
-use rayon::prelude::*;
-use redis::{Commands, Value};
+use opentelemetry::Context;
+use prost_types::Struct;
+use serde::Serialize;
 use tokio::sync::watch::Receiver;
 use tonic::Response;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, instrument, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+use crate::accumulator::Accumulator;
+use crate::cache::OverLimitCache;
+use crate::config_source::{Settings, Storage};
+use crate::metrics::Metrics;
+use crate::proto::envoy::config::core::v3::HeaderValue;
+use crate::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+use crate::proto::envoy::service::ratelimit::v3::rate_limit_response::{Code, DescriptorStatus};
 use crate::proto::envoy::service::ratelimit::v3::rate_limit_service_server::RateLimitService;
 use crate::proto::envoy::service::ratelimit::v3::{RateLimitRequest, RateLimitResponse};
-use crate::rate_limits::{Descriptor, RateLimit};
-use crate::response::limit_response;
+use crate::rate_limits::{Descriptor, Domain, NearLimitReport, RateLimit};
+use crate::response::{
+    descriptor_status, limit_response, near_limit_header, near_limit_metadata, rate_limit_headers,
+};
+use crate::store::{Counter, LocalStore, RedisStore, Store};
+use crate::telemetry;
 
-pub type RateLimitConfigs = HashMap<String, Vec<Descriptor>>;
+pub type RateLimitConfigs = HashMap<String, Domain>;
+
+/// The rate limit configs in use, along with where and when they were loaded
+#[derive(Serialize, Default)]
+pub struct LoadedConfigs {
+    /// Goes up by one every time the configs change, starting from 0 before
+    /// any configs are loaded
+    pub version: u64,
+    /// Seconds since the epoch
+    pub loaded_at: u64,
+    pub source: String,
+    pub configs: RateLimitConfigs,
+}
+
+impl LoadedConfigs {
+    /// Replaces the configs, unless they are the same as the current ones.
+    /// Returns whether they were replaced.
+    pub fn update(&mut self, configs: RateLimitConfigs, source: &str) -> bool {
+        if self.version > 0
+            && serde_json::to_value(&configs).ok() == serde_json::to_value(&self.configs).ok()
+        {
+            return false;
+        }
+        self.version += 1;
+        self.loaded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.source = source.to_owned();
+        self.configs = configs;
+        true
+    }
+}
+
+/// The decision on a request, before it is turned into a `RateLimitResponse`
+pub struct Decision {
+    pub statuses: Vec<DecidedStatus>,
+    pub headers: Vec<HeaderValue>,
+    pub metadata: Option<Struct>,
+}
+
+/// The decision on a request descriptor, with the unit multiplier of its
+/// current limit, which `DescriptorStatus` has no room for
+pub struct DecidedStatus {
+    pub status: DescriptorStatus,
+    pub unit_multiplier: Option<NonZeroU32>,
+}
+
+impl Decision {
+    pub fn over(&self) -> bool {
+        self.statuses
+            .iter()
+            .any(|status| status.status.code() == Code::OverLimit)
+    }
+}
+
+impl From<Decision> for RateLimitResponse {
+    fn from(decision: Decision) -> Self {
+        let over = decision.over();
+        let statuses = decision.statuses.into_iter().map(|s| s.status).collect();
+        limit_response(over, statuses, decision.headers, decision.metadata)
+    }
+}
+
+/// Entries at or above this share of their limit are near the limit, by default
+const NEAR_LIMIT_RATIO: f64 = 0.8;
+
+/// A rate limit that matched a request, either from the config
+/// or from an override sent along with a request descriptor
+struct Entry<'a> {
+    key: String,
+    limit: RateLimit,
+    interval: usize,
+    hits: u64,
+    config: Option<&'a Descriptor>,
+    /// The entries of the request descriptor, for rules with detailed metrics
+    detail: Option<String>,
+}
+
+impl Entry<'_> {
+    fn name(&self) -> Option<&str> {
+        self.config.and_then(|c| c.name.as_deref())
+    }
+
+    fn shadow_mode(&self) -> bool {
+        self.config.is_some_and(|c| c.shadow_mode)
+    }
+
+    fn approximate(&self) -> bool {
+        self.config.is_some_and(|c| c.approximate)
+    }
+
+    /// Identifies the rule in metrics, by its name, or otherwise its key and value
+    fn rule(&self) -> String {
+        match self.config {
+            Some(config) => match &config.name {
+                Some(name) => name.to_owned(),
+                None => format!("{}_{}", config.key, config.value),
+            },
+            None => "override".to_owned(),
+        }
+    }
+}
 
 pub struct Steward {
-    rx: Receiver<RateLimitConfigs>,
-    pool: r2d2::Pool<redis::Client>,
+    rx: Receiver<LoadedConfigs>,
+    store: Arc<dyn Store>,
+    key_prefix: Option<String>,
     ttl: usize,
+    aligned_windows: bool,
+    check_then_commit: bool,
+    zero_hits_addend_is_query: bool,
+    near_limit_ratio: f64,
+    over_limit_cache: Option<OverLimitCache>,
+    accumulator: Arc<Accumulator>,
+    metrics: Arc<Metrics>,
 }
 
 impl Steward {
     pub fn new(
-        redis_host: &str,
-        default_rate_ttl: usize,
-        rx: Receiver<RateLimitConfigs>,
-        pool_size: usize,
-    ) -> Self {
-        let manager = redis::Client::open(format!("redis://{redis_host}")).unwrap();
-        let pool = r2d2::Pool::builder()
-            .max_size(pool_size as u32)
-            .build(manager)
-            .unwrap();
+        settings: &Settings,
+        rx: Receiver<LoadedConfigs>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        let check_then_commit = settings.check_then_commit.unwrap_or(false);
+        let store: Arc<dyn Store> = match settings.storage.as_ref().unwrap_or(&Storage::Redis) {
+            Storage::Redis => {
+                let redis_host = settings
+                    .redis_host
+                    .as_deref()
+                    .ok_or("redis_host is required with redis storage")?;
+                Arc::new(RedisStore::new(
+                    redis_host,
+                    settings.redis_connections.unwrap_or(1),
+                    Duration::from_millis(settings.redis_timeout_ms.unwrap_or(1000)),
+                    check_then_commit,
+                    settings.default_ttl,
+                )?)
+            }
+            Storage::Local => {
+                let store = Arc::new(LocalStore::new(check_then_commit, settings.default_ttl));
+                let sweep_store = store.clone();
+                thread::spawn(move || loop {
+                    thread::sleep(Duration::from_secs(1));
+                    sweep_store.sweep();
+                });
+                store
+            }
+        };
 
-        Self {
+        let accumulator = Arc::new(Accumulator::default());
+        let sync_interval = settings.approximate_sync_interval_ms.unwrap_or(100);
+        let (sync_store, sync_accumulator) = (store.clone(), accumulator.clone());
+        let sync_metrics = metrics.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(sync_interval));
+            sync_accumulator_to_store(sync_store.as_ref(), &sync_accumulator, &sync_metrics);
+        });
+
+        Ok(Self {
             rx,
-            pool,
-            ttl: default_rate_ttl,
+            store,
+            key_prefix: settings.key_prefix.clone(),
+            ttl: settings.default_ttl,
+            aligned_windows: settings.aligned_windows.unwrap_or(false),
+            check_then_commit,
+            zero_hits_addend_is_query: settings.zero_hits_addend_is_query.unwrap_or(false),
+            near_limit_ratio: settings.near_limit_ratio.unwrap_or(NEAR_LIMIT_RATIO),
+            over_limit_cache: settings
+                .local_cache_size
+                .filter(|size| *size > 0)
+                .map(OverLimitCache::new),
+            accumulator,
+            metrics,
+        })
+    }
+
+    pub fn store(&self) -> Arc<dyn Store> {
+        self.store.clone()
+    }
+
+    /// Syncs the pending hits of approximate counters to the store right away,
+    /// so that they are not lost when the service stops
+    pub fn flush(&self) {
+        sync_accumulator_to_store(self.store.as_ref(), &self.accumulator, &self.metrics);
+    }
+
+    /// Increments every counter in the store, see [`Store::increment`].
+    /// When the store fails, every counter has a rate of 0.
+    #[instrument(skip_all, fields(counters = counters.len()))]
+    fn increment_entries(&self, counters: &[&Counter]) -> Vec<(i64, Option<usize>)> {
+        let start = Instant::now();
+        let results = self.store.increment(counters);
+        self.metrics.store_call(start.elapsed(), results.is_ok());
+        if let Some((connections, idle, max)) = self.store.pool_state() {
+            self.metrics.pool_state(connections, idle, max);
+        }
+        match results {
+            Ok(results) => results,
+            Err(e) => {
+                error!("{e}");
+                vec![(0, None); counters.len()]
+            }
         }
     }
 
-    fn increment_entry(&self, key: &str, hits: &u32, interval: Option<usize>) -> i64 {
-        let mut current_rate = 0;
-        match self.pool.get() {
-            Ok(mut conn) => {
-                let interval = interval.unwrap_or(self.ttl);
-                let incremented_value = conn.incr(key, hits.to_owned());
-                if let Ok(Value::Int(n)) = incremented_value {
-                    current_rate = n;
-                }
-                // Key was created, because 1 means that INCR was performed on either 0 or null key
-                // Therefore, we should set it to expire
-                if current_rate == 1 {
-                    match conn.expire::<&str, u64>(key, interval) {
-                        Err(e) => error!("Failed to set expiry for key: {e}"),
-                        Ok(_) => debug!("Set expiry for key to {interval}"),
-                    }
+    /// Reports the hits of every entry, and whether it is over or near its limit.
+    /// Returns the rules of the entries that are near their limit.
+    fn record_entries(
+        &self,
+        domain: &str,
+        entries: &[Entry],
+        results: &[(i64, Option<usize>)],
+        shadow_mode: bool,
+    ) -> Vec<String> {
+        let mut near_limit = vec![];
+        for (entry, (rate, _)) in entries.iter().zip(results.iter()) {
+            let (rule, detail) = (entry.rule(), entry.detail.as_deref());
+            let limit = entry.limit.requests_per_unit;
+            self.metrics.hits(domain, &rule, detail, entry.hits);
+            if *rate > limit && (shadow_mode || entry.shadow_mode()) {
+                self.metrics.shadow_mode(domain, &rule, detail);
+            } else if *rate > limit {
+                self.metrics.over_limit(domain, &rule, detail);
+            } else if *rate as f64 >= limit as f64 * self.near_limit_ratio {
+                self.metrics.near_limit(domain, &rule, detail);
+                info!(rate_limit_key=%entry.key, rule=%rule, detail=?detail, limit=%limit, client_rate=%rate, "Request is near the limit");
+                if !near_limit.contains(&rule) {
+                    near_limit.push(rule);
                 }
             }
-            Err(e) => error!("Failed to acquire database connection: {e}"),
         }
-        current_rate
+        near_limit
+    }
+}
+
+/// Flushes the hits of approximate counters to the store, and records their
+/// updated rates, which include the hits of every other instance
+fn sync_accumulator_to_store(store: &dyn Store, accumulator: &Accumulator, metrics: &Metrics) {
+    let pending = accumulator.drain();
+    if pending.is_empty() {
+        return;
+    }
+    let counters: Vec<_> = pending
+        .iter()
+        .map(|(key, hits, interval)| Counter {
+            key: key.to_owned(),
+            interval: Some(*interval),
+            limit: None,
+            hits: *hits,
+            approximate: true,
+        })
+        .collect();
+    let _span = info_span!("sync_approximate_counters", counters = counters.len()).entered();
+    let start = Instant::now();
+    let results = store.increment(&counters.iter().collect::<Vec<_>>());
+    metrics.store_call(start.elapsed(), results.is_ok());
+    match results {
+        Ok(results) => {
+            for (counter, (rate, _)) in counters.iter().zip(results) {
+                accumulator.synced(&counter.key, rate);
+            }
+        }
+        Err(e) => {
+            error!("Failed to sync approximate counters: {e}");
+            for counter in counters.iter() {
+                accumulator.restore(&counter.key, counter.hits);
+            }
+        }
     }
 }
 
-fn collect_rate_limit_entries(
+/// Adds entries for every configured rate limit that matches the request, in the
+/// order of the request descriptors, and then the order of the config.
+/// Returns the indexes of the entries that matched each request descriptor.
+/// Request descriptors with an entry that exactly matches an unlimited config
+/// have no entries at all, and configs that are replaced by another matching
+/// config are left out.
+/// Request descriptors with a rate limit override only have an entry for the
+/// override, unless overrides are disallowed for the domain.
+/// Entries add the hits of their request descriptor, or of the request if the
+/// descriptor has none; an entry shared by several descriptors adds the most.
+#[instrument(skip_all, fields(descriptors = request.descriptors.len()))]
+fn collect_rate_limit_entries<'a>(
     request: &RateLimitRequest,
-    rate_limits: Vec<Descriptor>,
-    entries: &mut HashMap<String, RateLimit>,
-) {
+    domain: &'a Domain,
+    hits_addend: u64,
+    entries: &mut Vec<Entry<'a>>,
+) -> Result<Vec<Vec<usize>>, String> {
     debug!("Reading descriptor entries from request");
+    let mut descriptor_candidates = Vec::with_capacity(request.descriptors.len());
     for descriptor in request.descriptors.iter() {
         debug!("Descriptor: {descriptor:?}");
+        let mut candidates = vec![];
+        let mut unlimited = false;
+        let hits = descriptor.hits_addend.unwrap_or(hits_addend);
 
-        let limit_override = descriptor.limit.as_ref().map(RateLimit::from);
+        let limit_override = match &descriptor.limit {
+            Some(_) if domain.settings.disallow_overrides => {
+                warn!("Ignoring rate limit override, overrides are disallowed for the domain");
+                None
+            }
+            Some(override_) => Some(RateLimit::from(override_)),
+            None => None,
+        };
 
         for entry in descriptor.entries.iter() {
             let key = create_descriptor_key(&request.domain, &entry.key, &entry.value);
-            for limit in rate_limits.iter() {
-                let requests_per_unit = limit.rate_limit.requests_per_unit;
-                let config_key = create_rl_config_key(&request.domain, limit, requests_per_unit);
-                if config_key.starts_with(&key) {
-                    debug!("Rate limit config matches descriptor entry: {config_key}");
-                    if let Some(override_) = limit_override.clone() {
-                        entries.insert(config_key, override_);
-                    } else {
-                        entries.insert(config_key, limit.rate_limit.to_owned());
+            for limit in domain.descriptors.iter() {
+                let config_key =
+                    create_rl_config_key(&request.domain, limit, limit.rate_limit.as_ref());
+                // Unlimited configs only exempt the exact key and value, as
+                // a prefix would also exempt values such as 10.0.0.1 by 10.0.0.10
+                if limit.unlimited {
+                    if entry.key == limit.key && entry.value == limit.value {
+                        info!("Rate limit config {config_key} is unlimited");
+                        unlimited = true;
                     }
-                } else {
+                    continue;
+                }
+                if !config_key.starts_with(&key) {
                     debug!("{key} did not match {config_key}");
+                    continue;
                 }
+                debug!("Rate limit config matches descriptor entry: {config_key}");
+                if limit_override.is_some() {
+                    continue;
+                }
+                let rate_limit = match &limit.rate_limit {
+                    Some(rate_limit) => rate_limit.to_owned(),
+                    None => {
+                        warn!("Rate limit config {config_key} has no rate limit");
+                        continue;
+                    }
+                };
+                let interval = match rate_limit.interval() {
+                    Ok(interval) => interval,
+                    Err(e) => {
+                        error!("Rate limit config {config_key} is invalid: {e}");
+                        continue;
+                    }
+                };
+                candidates.push(Entry {
+                    key: config_key,
+                    limit: rate_limit,
+                    interval,
+                    hits,
+                    config: Some(limit),
+                    detail: limit
+                        .detailed_metric
+                        .then(|| create_metric_detail(descriptor)),
+                });
             }
         }
+
+        if let Some(override_) = limit_override {
+            let interval = override_
+                .interval()
+                .map_err(|e| format!("Invalid rate limit override: {e}"))?;
+            let key = create_override_key(&request.domain, descriptor, &override_);
+            debug!("Descriptor has a rate limit override: {key}");
+            candidates.push(Entry {
+                key,
+                limit: override_,
+                interval,
+                hits,
+                config: None,
+                detail: None,
+            });
+        }
+
+        if unlimited {
+            debug!("Descriptor is unlimited, skipping its rate limits");
+            candidates.clear();
+        }
+        descriptor_candidates.push(candidates);
+    }
+
+    let replaced: HashSet<&str> = descriptor_candidates
+        .iter()
+        .flatten()
+        .filter_map(|candidate| candidate.config)
+        .flat_map(|limit| limit.replaces.iter().map(|r| r.name.as_str()))
+        .collect();
+
+    let mut descriptor_entries = Vec::with_capacity(descriptor_candidates.len());
+    for candidates in descriptor_candidates {
+        let mut matched = vec![];
+        for candidate in candidates {
+            if let Some(name) = candidate.name().filter(|n| replaced.contains(*n)) {
+                debug!("Rate limit config {} is replaced, as {name}", candidate.key);
+                continue;
+            }
+            // Descriptors that match the same config share one counter
+            let index = match entries.iter().position(|e| e.key == candidate.key) {
+                Some(index) => {
+                    entries[index].hits = entries[index].hits.max(candidate.hits);
+                    index
+                }
+                None => {
+                    entries.push(candidate);
+                    entries.len() - 1
+                }
+            };
+            if !matched.contains(&index) {
+                matched.push(index);
+            }
+        }
+        descriptor_entries.push(matched);
+    }
+    Ok(descriptor_entries)
+}
+
+/// Checks every entry of a request descriptor, and reports the tightest one;
+/// that is the first one it is over, or otherwise the one with the least
+/// remaining requests.
+/// Like the Lyft service, an entry is only over once its rate goes past the
+/// limit, so a limit of 5 allows 5 requests and then has 0 remaining.
+/// Entries in shadow mode are never reported as over the limit.
+fn check_descriptor(
+    indexes: &[usize],
+    entries: &[Entry],
+    results: &[(i64, Option<usize>)],
+    shadow_mode: bool,
+) -> (DescriptorStatus, Option<usize>) {
+    let mut tightest: Option<(DescriptorStatus, usize)> = None;
+    for &i in indexes.iter() {
+        let entry = &entries[i];
+        let (entry_key, limit) = (&entry.key, &entry.limit);
+        let (rate, reset) = results[i];
+        let requests_per_unit = limit.requests_per_unit;
+        debug!("Checking if {entry_key} should rate limit");
+        info!("Checking if rate ({rate}) is over limit ({requests_per_unit}) for {entry_key}");
+        let mut over = rate > requests_per_unit;
+        if over && (shadow_mode || entry.shadow_mode()) {
+            warn!(rate_limit_key=%entry_key, limit=%requests_per_unit, client_rate=%rate, "Request would be over the limit, but is allowed by shadow mode");
+            over = false;
+        } else if over {
+            warn!(rate_limit_key=%entry_key, limit=%requests_per_unit, client_rate=%rate, "Request is over the limit");
+        }
+        let status = descriptor_status(
+            over,
+            Some(limit),
+            entry.name(),
+            requests_per_unit - rate,
+            reset,
+        );
+        let tighter = match &tightest {
+            Some((current, _)) => tightness(&status) > tightness(current),
+            None => true,
+        };
+        if tighter {
+            tightest = Some((status, i));
+        }
     }
+    match tightest {
+        Some((status, i)) => (status, Some(i)),
+        None => (descriptor_status(false, None, None, 0, None), None),
+    }
+}
+
+/// Orders statuses so that being over the limit, and then having
+/// fewer remaining requests, sorts higher
+fn tightness(status: &DescriptorStatus) -> (bool, i64) {
+    (
+        status.code() == Code::OverLimit,
+        -(status.limit_remaining as i64),
+    )
+}
+
+/// Appends the index of the current fixed window to a counter key, so that every
+/// instance counts into the same window and resets happen on unit boundaries.
+/// Returns the key along with the number of seconds left in the window.
+fn create_window_key(key: &str, interval: usize, now: u64) -> (String, usize) {
+    let interval = interval.max(1) as u64;
+    let window = now / interval;
+    let remaining = interval - now % interval;
+    (format!("{key}_{window}"), remaining as usize)
+}
+
+/// Identifies a request descriptor in metrics, as `key_value.key_value`
+fn create_metric_detail(descriptor: &RateLimitDescriptor) -> String {
+    descriptor
+        .entries
+        .iter()
+        .map(|entry| format!("{}_{}", entry.key, entry.value))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Namespaces a counter key by the key prefix of the deployment and the
+/// generation of the domain config, when they are set
+fn create_counter_key(prefix: Option<&str>, generation: Option<u64>, key: &str) -> String {
+    let mut result = String::new();
+    if let Some(prefix) = prefix {
+        result.push_str(prefix);
+        result.push(':');
+    }
+    if let Some(generation) = generation {
+        result.push('v');
+        result.push_str(&generation.to_string());
+        result.push(':');
+    }
+    result.push_str(key);
+    result
 }
 
 fn create_descriptor_key(domain: &str, key: &str, value: &str) -> String {
@@ -103,69 +543,741 @@ fn create_descriptor_key(domain: &str, key: &str, value: &str) -> String {
     result
 }
 
-fn create_rl_config_key(domain: &str, limit: &Descriptor, requests_per_unit: i64) -> String {
-    let mut result = String::with_capacity(
-        domain.len()
-            + limit.key.len()
-            + limit.value.len()
-            + requests_per_unit.to_string().len()
-            + 5,
-    );
+/// Overrides are keyed by every entry of the request descriptor, along with
+/// the override itself. Their keys end in `_override`, so that they never
+/// share counters with the config, whose keys end in their unit.
+fn create_override_key(
+    domain: &str,
+    descriptor: &RateLimitDescriptor,
+    rate_limit: &RateLimit,
+) -> String {
+    let mut result = String::from(domain);
+    for entry in descriptor.entries.iter() {
+        result.push_str(&entry.key);
+        result.push_str(&entry.value);
+    }
+    result.push_str(&rate_limit.requests_per_unit.to_string());
+    result.push_str(&(rate_limit.unit.clone() as i32).to_string());
+    result.push_str("_override");
+    result
+}
+
+fn create_rl_config_key(
+    domain: &str,
+    limit: &Descriptor,
+    rate_limit: Option<&RateLimit>,
+) -> String {
+    let mut result = String::with_capacity(domain.len() + limit.key.len() + limit.value.len() + 32);
     result.push_str(domain);
     result.push_str(&limit.key);
     result.push_str(&limit.value);
-    result.push_str(&requests_per_unit.to_string());
-    result.push_str(&(limit.rate_limit.unit.clone() as i32).to_string());
+    if let Some(rate_limit) = rate_limit {
+        result.push_str(&rate_limit.requests_per_unit.to_string());
+        result.push_str(&(rate_limit.unit.clone() as i32).to_string());
+        if let Some(multiplier) = rate_limit.unit_multiplier {
+            result.push('x');
+            result.push_str(&multiplier.to_string());
+        }
+    }
     result
 }
 
-#[tonic::async_trait]
-impl RateLimitService for Steward {
-    async fn should_rate_limit(
-        &self,
-        request: tonic::Request<RateLimitRequest>,
-    ) -> Result<Response<RateLimitResponse>, tonic::Status> {
-        let request = request.into_inner();
+impl Steward {
+    /// Decides on a rate limit request, as part of the given trace.
+    /// Fails when the request is invalid.
+    pub fn decide(&self, request: RateLimitRequest, parent: Context) -> Result<Decision, String> {
+        let span = info_span!("should_rate_limit", domain = %request.domain);
+        span.set_parent(parent);
+        let _span = span.entered();
         debug!("Received request");
-        if let Some(rate_limits) = self.rx.borrow().get(&request.domain) {
+        let loaded = self.rx.borrow();
+        let domain = loaded.configs.get(&request.domain);
+        // Domains that are not configured share one label, so that clients
+        // cannot add metric series at will
+        self.metrics
+            .request(domain.map_or("unknown", |_| request.domain.as_str()));
+        let _timer = self.metrics.time_request();
+        let mut statuses: Vec<DecidedStatus> = Vec::with_capacity(request.descriptors.len());
+        let mut headers = vec![];
+        let mut metadata = None;
+        // Envoy sends 0 when no hits_addend is set, which adds a single hit
+        let hits_addend = match request.hits_addend {
+            0 if self.zero_hits_addend_is_query => 0,
+            0 => 1,
+            hits => hits as u64,
+        };
+        if let Some(domain) = domain {
             debug!("Loaded rate limits from config source");
-            let mut entries = HashMap::with_capacity(request.descriptors.len());
+            let mut entries = Vec::with_capacity(request.descriptors.len());
 
-            collect_rate_limit_entries(&request, rate_limits.to_vec(), &mut entries);
+            let descriptor_entries =
+                match collect_rate_limit_entries(&request, domain, hits_addend, &mut entries) {
+                    Ok(descriptor_entries) => descriptor_entries,
+                    Err(e) => {
+                        error!("Could not collect rate limits for request: {e}");
+                        return Err(e);
+                    }
+                };
 
-            let mut results = HashMap::with_capacity(entries.len());
-            let injector = Injector::new();
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            let shadow_mode = domain.settings.shadow_mode;
+            let counters: Vec<_> = entries
+                .iter()
+                .map(|entry| {
+                    let (counter_key, ttl) = match self.aligned_windows {
+                        true => create_window_key(&entry.key, entry.interval, now),
+                        false => (entry.key.to_owned(), entry.interval),
+                    };
+                    Counter {
+                        key: create_counter_key(
+                            self.key_prefix.as_deref(),
+                            domain.settings.generation,
+                            &counter_key,
+                        ),
+                        interval: Some(ttl),
+                        limit: match shadow_mode || entry.shadow_mode() {
+                            true => None,
+                            false => Some(entry.limit.requests_per_unit),
+                        },
+                        hits: entry.hits,
+                        approximate: entry.approximate(),
+                    }
+                })
+                .collect();
 
-            entries.par_iter().for_each(|(key, limit)| {
-                let injector = &injector;
-                let interval = limit.unit.clone().into();
-                info!("Incrementing entry '{key}' in db");
-                let val = self.increment_entry(key, &request.hits_addend.max(1), Some(interval));
-                injector.push((key, val));
-            });
+            let mut results = vec![(0, None); counters.len()];
+            let mut uncached = Vec::with_capacity(counters.len());
+            for (i, counter) in counters.iter().enumerate() {
+                if counter.approximate {
+                    let rate = self.accumulator.add(
+                        &counter.key,
+                        counter.hits,
+                        counter.interval.unwrap_or(self.ttl),
+                    );
+                    debug!("Entry {} has approximate rate of {rate}", counter.key);
+                    results[i] = (rate, counter.interval.filter(|_| self.aligned_windows));
+                    continue;
+                }
+                // Cached keys are at their limit, so any hit takes them over it
+                let cached = match (&self.over_limit_cache, counter.limit) {
+                    (Some(cache), Some(limit)) if counter.hits > 0 => cache
+                        .get(&counter.key)
+                        .map(|reset| (limit + counter.hits as i64, Some(reset.as_secs() as usize))),
+                    _ => None,
+                };
+                match cached {
+                    Some(result) => {
+                        debug!("Entry {} is over the limit in local cache", counter.key);
+                        results[i] = result;
+                    }
+                    None => uncached.push(i),
+                }
+            }
 
-            while let Steal::Success((key, value)) = injector.steal() {
-                debug!("Entry {key} has rate of {value}");
-                results.insert(key, value);
+            // With check-then-commit, a request that is already known to be over
+            // a limit does not increment anything, so there is nothing to do in the db
+            let already_over = counters
+                .iter()
+                .zip(results.iter())
+                .any(|(counter, (rate, _))| counter.limit.is_some_and(|limit| *rate > limit));
+            let skip_db = self.check_then_commit && already_over;
+            if !uncached.is_empty() && !skip_db {
+                info!("Incrementing {} entries in store", uncached.len());
+                let rates = self
+                    .increment_entries(&uncached.iter().map(|&i| &counters[i]).collect::<Vec<_>>());
+                // With check-then-commit, a rejected request increments nothing,
+                // and its rates are what the counters would have been
+                let rejected = self.check_then_commit
+                    && uncached.iter().zip(rates.iter()).any(|(&i, (rate, _))| {
+                        counters[i].limit.is_some_and(|l| l >= 0 && *rate > l)
+                    });
+                for (&i, (rate, ttl)) in uncached.iter().zip(rates) {
+                    let counter = &counters[i];
+                    debug!("Entry {} has rate of {rate}", counter.key);
+                    // Aligned windows know their reset time without asking the db
+                    let reset = match self.aligned_windows {
+                        true => counter.interval,
+                        false => ttl,
+                    };
+                    let committed = match rejected {
+                        true => rate - counter.hits as i64,
+                        false => rate,
+                    };
+                    if let (Some(cache), Some(limit), Some(reset)) =
+                        (&self.over_limit_cache, counter.limit, reset)
+                    {
+                        if committed >= limit {
+                            cache.insert(&counter.key, Duration::from_secs(reset as u64));
+                        }
+                    }
+                    results[i] = (rate, reset);
+                }
             }
-            debug!("Results: {:?}", results);
+
+            let near_limit = self.record_entries(&request.domain, &entries, &results, shadow_mode);
 
             debug!("Checking if any rate limit has been hit");
-            for (entry_key, limit) in entries.iter() {
-                debug!("Checking if {entry_key} should rate limit");
-                let requests_per_unit = limit.requests_per_unit;
-                let rate = results.get(entry_key).unwrap_or(&0);
-                info!(
-                    "Checking if rate ({rate}) is over limit ({requests_per_unit}) for {entry_key}"
+            let mut tightest: Option<(usize, usize)> = None;
+            for indexes in descriptor_entries.iter() {
+                let (status, entry) = check_descriptor(indexes, &entries, &results, shadow_mode);
+                if let Some(entry) = entry {
+                    let tighter = match tightest {
+                        Some((i, _)) => tightness(&status) > tightness(&statuses[i].status),
+                        None => true,
+                    };
+                    if tighter {
+                        tightest = Some((statuses.len(), entry));
+                    }
+                }
+                statuses.push(DecidedStatus {
+                    status,
+                    unit_multiplier: entry.and_then(|i| entries[i].limit.unit_multiplier),
+                });
+            }
+
+            if let (Some(format), Some((i, entry))) =
+                (&domain.settings.rate_limit_headers, tightest)
+            {
+                let status = &statuses[i].status;
+                let reset = status
+                    .duration_until_reset
+                    .as_ref()
+                    .map(|d| d.seconds as usize);
+                let entry = &entries[entry];
+                headers = rate_limit_headers(
+                    format,
+                    &entry.limit,
+                    entry.interval,
+                    status.limit_remaining,
+                    reset,
                 );
-                if rate >= &requests_per_unit {
-                    warn!(rate_limit_key=%entry_key, limit=%requests_per_unit, client_rate=%rate, "Request is over the limit");
-                    return Ok(Response::new(limit_response(true)));
+            }
+
+            match (&domain.settings.report_near_limit, near_limit.is_empty()) {
+                (Some(NearLimitReport::Header), false) => headers.push(near_limit_header()),
+                (Some(NearLimitReport::Metadata), false) => {
+                    metadata = Some(near_limit_metadata(&near_limit))
                 }
+                _ => {}
             }
         } else {
             error!("Could not obtain rate limit config from channel");
+            statuses.resize_with(request.descriptors.len(), || DecidedStatus {
+                status: descriptor_status(false, None, None, 0, None),
+                unit_multiplier: None,
+            });
+        }
+        Ok(Decision {
+            statuses,
+            headers,
+            metadata,
+        })
+    }
+}
+
+/// Shared with the admin endpoints. Requests are decided on blocking threads,
+/// as the store is called synchronously, so that a slow store cannot hold up
+/// the health checks running on the same runtime.
+#[tonic::async_trait]
+impl RateLimitService for Arc<Steward> {
+    async fn should_rate_limit(
+        &self,
+        request: tonic::Request<RateLimitRequest>,
+    ) -> Result<Response<RateLimitResponse>, tonic::Status> {
+        let parent = telemetry::extract_context(request.metadata());
+        let steward = self.clone();
+        let decision =
+            tokio::task::spawn_blocking(move || steward.decide(request.into_inner(), parent))
+                .await
+                .map_err(|e| tonic::Status::internal(e.to_string()))?
+                .map_err(tonic::Status::invalid_argument)?;
+        Ok(Response::new(decision.into()))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::{json, Value};
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor;
+    use crate::rate_limits::{RateLimitHeaders, Unit};
+
+    /// A steward that keeps its counters in memory, with the given settings
+    /// on top of the required ones
+    pub(crate) fn steward(settings: Value, configs: Value) -> Steward {
+        let mut merged = json!({
+            "listen": {"addr": "127.0.0.1", "port": 0},
+            "rate_limit_configs": {"http": "http://localhost/"},
+            "storage": "local",
+            "default_ttl": 60,
+        });
+        merged
+            .as_object_mut()
+            .unwrap()
+            .extend(settings.as_object().unwrap().clone());
+        let settings: Settings = serde_json::from_value(merged).unwrap();
+        let mut loaded = LoadedConfigs::default();
+        loaded.update(serde_json::from_value(configs).unwrap(), "test");
+        let (_, rx) = watch::channel(loaded);
+        Steward::new(&settings, rx, Arc::default()).unwrap()
+    }
+
+    fn descriptor(entries: &[(&str, &str)]) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: entries
+                .iter()
+                .map(|(key, value)| rate_limit_descriptor::Entry {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            limit: None,
+            hits_addend: None,
+        }
+    }
+
+    fn request(descriptors: Vec<RateLimitDescriptor>) -> RateLimitRequest {
+        RateLimitRequest {
+            domain: "domain".to_owned(),
+            descriptors,
+            hits_addend: 0,
         }
-        Ok(Response::new(limit_response(false)))
+    }
+
+    /// Returns the overall code, along with the code and remaining requests of
+    /// every status
+    fn decide(steward: &Steward, request: &RateLimitRequest) -> (Code, Vec<(Code, u32)>) {
+        let response =
+            RateLimitResponse::from(steward.decide(request.clone(), Context::new()).unwrap());
+        let statuses = response
+            .statuses
+            .iter()
+            .map(|status| (status.code(), status.limit_remaining))
+            .collect();
+        (response.overall_code(), statuses)
+    }
+
+    fn limit(key: &str, value: &str, requests_per_unit: i64) -> Value {
+        json!({
+            "key": key,
+            "value": value,
+            "rate_limit": {"unit": "minutes", "requests_per_unit": requests_per_unit},
+        })
+    }
+
+    #[test]
+    fn window_keys_count_into_the_current_window() {
+        assert_eq!(create_window_key("key", 60, 120), ("key_2".to_owned(), 60));
+        assert_eq!(create_window_key("key", 60, 179), ("key_2".to_owned(), 1));
+        assert_eq!(create_window_key("key", 60, 180), ("key_3".to_owned(), 60));
+        assert_eq!(create_window_key("key", 300, 299), ("key_0".to_owned(), 1));
+        // Windows of 0 seconds are treated as 1 second long
+        assert_eq!(create_window_key("key", 0, 180), ("key_180".to_owned(), 1));
+    }
+
+    #[test]
+    fn unknown_domains_share_one_metric_label() {
+        let steward = steward(json!({}), json!({"domain": [limit("a", "1", 10)]}));
+        let mut unknown = request(vec![descriptor(&[("a", "1")])]);
+        for domain in ["other", "another"] {
+            unknown.domain = domain.to_owned();
+            decide(&steward, &unknown);
+        }
+        decide(&steward, &request(vec![descriptor(&[("a", "1")])]));
+        let rendered = steward.metrics.render();
+        assert!(rendered.contains(r#"steward_requests_total{domain="unknown"} 2"#));
+        assert!(rendered.contains(r#"steward_requests_total{domain="domain"} 1"#));
+        assert!(!rendered.contains("other"));
+    }
+
+    #[test]
+    fn evaluates_every_descriptor_after_one_is_over() {
+        let steward = steward(
+            json!({}),
+            json!({"domain": [limit("a", "1", 1), limit("b", "1", 10)]}),
+        );
+        let both = request(vec![descriptor(&[("a", "1")]), descriptor(&[("b", "1")])]);
+        assert_eq!(
+            decide(&steward, &both),
+            (Code::Ok, vec![(Code::Ok, 0), (Code::Ok, 9)])
+        );
+        assert_eq!(
+            decide(&steward, &both),
+            (Code::OverLimit, vec![(Code::OverLimit, 0), (Code::Ok, 8)])
+        );
+        assert_eq!(
+            decide(&steward, &both),
+            (Code::OverLimit, vec![(Code::OverLimit, 0), (Code::Ok, 7)])
+        );
+    }
+
+    #[test]
+    fn reports_the_tightest_limit_of_a_descriptor() {
+        let steward = steward(
+            json!({}),
+            json!({"domain": [limit("a", "1", 10), limit("a", "1", 2)]}),
+        );
+        let a = request(vec![descriptor(&[("a", "1")])]);
+        let decision = steward.decide(a.clone(), Context::new()).unwrap();
+        let status = &decision.statuses[0].status;
+        assert_eq!(status.limit_remaining, 1);
+        assert_eq!(status.current_limit.as_ref().unwrap().requests_per_unit, 2);
+
+        assert_eq!(decide(&steward, &a), (Code::Ok, vec![(Code::Ok, 0)]));
+        assert_eq!(
+            decide(&steward, &a),
+            (Code::OverLimit, vec![(Code::OverLimit, 0)])
+        );
+    }
+
+    #[test]
+    fn answers_every_descriptor_in_order() {
+        let steward = steward(json!({}), json!({"domain": [limit("a", "1", 5)]}));
+        let decision = steward
+            .decide(
+                request(vec![
+                    descriptor(&[("unknown", "1")]),
+                    descriptor(&[("a", "1")]),
+                ]),
+                Context::new(),
+            )
+            .unwrap();
+        assert_eq!(decision.statuses.len(), 2);
+        assert!(decision.statuses[0].status.current_limit.is_none());
+        assert_eq!(decision.statuses[1].status.limit_remaining, 4);
+    }
+
+    #[test]
+    fn check_then_commit_increments_nothing_when_any_counter_would_be_over() {
+        let steward = steward(
+            json!({"check_then_commit": true}),
+            json!({"domain": [limit("a", "1", 1), limit("b", "1", 10)]}),
+        );
+        let both = request(vec![descriptor(&[("a", "1")]), descriptor(&[("b", "1")])]);
+        let b = request(vec![descriptor(&[("b", "1")])]);
+        assert_eq!(
+            decide(&steward, &both),
+            (Code::Ok, vec![(Code::Ok, 0), (Code::Ok, 9)])
+        );
+        for _ in 0..3 {
+            assert_eq!(decide(&steward, &both).0, Code::OverLimit);
+        }
+        // Only the first request took quota from b
+        assert_eq!(decide(&steward, &b), (Code::Ok, vec![(Code::Ok, 8)]));
+    }
+
+    #[test]
+    fn check_then_commit_ignores_limits_in_shadow_mode() {
+        let mut shadow = limit("a", "1", 1);
+        shadow["shadow_mode"] = json!(true);
+        let steward = steward(
+            json!({"check_then_commit": true}),
+            json!({"domain": [shadow, limit("b", "1", 10)]}),
+        );
+        let both = request(vec![descriptor(&[("a", "1")]), descriptor(&[("b", "1")])]);
+        for remaining in (7..10).rev() {
+            assert_eq!(
+                decide(&steward, &both),
+                (Code::Ok, vec![(Code::Ok, 0), (Code::Ok, remaining)])
+            );
+        }
+    }
+
+    #[test]
+    fn unlimited_configs_only_exempt_their_exact_value() {
+        let configs = json!({"domain": [
+            {"key": "ip", "value": "10.0.0.10", "unlimited": true},
+            limit("ip", "10.0.0.1", 1),
+        ]});
+        let exempt = request(vec![descriptor(&[("ip", "10.0.0.10")])]);
+        let allowlisted = steward(json!({}), configs.clone());
+        for _ in 0..3 {
+            assert_eq!(
+                decide(&allowlisted, &exempt),
+                (Code::Ok, vec![(Code::Ok, 0)])
+            );
+        }
+        // Values that the unlimited value starts with are still limited
+        for value in ["10.0.0.1", "10", ""] {
+            let steward = steward(json!({}), configs.clone());
+            let limited = request(vec![descriptor(&[("ip", value)])]);
+            assert_eq!(decide(&steward, &limited).0, Code::Ok, "{value}");
+            assert_eq!(decide(&steward, &limited).0, Code::OverLimit, "{value}");
+        }
+    }
+
+    fn headers(headers: &[HeaderValue]) -> Vec<(&str, &str)> {
+        headers
+            .iter()
+            .map(|header| (header.key.as_str(), header.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn rate_limit_headers_describe_the_limit_and_its_window() {
+        let limit: RateLimit = serde_json::from_value(
+            json!({"unit": "minutes", "requests_per_unit": 10, "unit_multiplier": 5}),
+        )
+        .unwrap();
+        let x_rate_limit = RateLimitHeaders::XRateLimit;
+        assert_eq!(
+            headers(&rate_limit_headers(
+                &x_rate_limit,
+                &limit,
+                300,
+                4,
+                Some(120)
+            )),
+            vec![
+                ("X-RateLimit-Limit", "10, 10;w=300"),
+                ("X-RateLimit-Remaining", "4"),
+                ("X-RateLimit-Reset", "120"),
+            ]
+        );
+        assert_eq!(
+            headers(&rate_limit_headers(&x_rate_limit, &limit, 300, 4, None)),
+            vec![
+                ("X-RateLimit-Limit", "10, 10;w=300"),
+                ("X-RateLimit-Remaining", "4"),
+            ]
+        );
+        let ietf = RateLimitHeaders::Ietf;
+        assert_eq!(
+            headers(&rate_limit_headers(&ietf, &limit, 300, 4, Some(120))),
+            vec![
+                ("RateLimit-Policy", "10;w=300"),
+                ("RateLimit", "limit=10, remaining=4, reset=120"),
+            ]
+        );
+        assert_eq!(
+            headers(&rate_limit_headers(&ietf, &limit, 300, 0, None)),
+            vec![
+                ("RateLimit-Policy", "10;w=300"),
+                ("RateLimit", "limit=10, remaining=0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_limit_headers_follow_the_tightest_descriptor() {
+        let steward = steward(
+            json!({"aligned_windows": true}),
+            json!({"domain": {
+                "descriptors": [limit("a", "1", 10), limit("b", "1", 3)],
+                "rate_limit_headers": "x-ratelimit",
+            }}),
+        );
+        let both = request(vec![descriptor(&[("a", "1")]), descriptor(&[("b", "1")])]);
+        let limit_and_remaining = |request: &RateLimitRequest| {
+            let decision = steward.decide(request.clone(), Context::new()).unwrap();
+            assert_eq!(decision.headers[2].key, "X-RateLimit-Reset");
+            headers(&decision.headers[..2])
+                .iter()
+                .map(|(_, value)| value.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(limit_and_remaining(&both), vec!["3, 3;w=60", "2"]);
+        for _ in 0..3 {
+            limit_and_remaining(&both);
+        }
+        assert_eq!(limit_and_remaining(&both), vec!["3, 3;w=60", "0"]);
+        let a = request(vec![descriptor(&[("a", "1")])]);
+        assert_eq!(limit_and_remaining(&a), vec!["10, 10;w=60", "4"]);
+    }
+
+    /// Returns the keys of the entries that each request descriptor matched
+    fn collect(domain: Value, request: &RateLimitRequest) -> Result<Vec<Vec<String>>, String> {
+        let domain: Domain = serde_json::from_value(domain).unwrap();
+        let mut entries = vec![];
+        let descriptor_entries = collect_rate_limit_entries(request, &domain, 1, &mut entries)?;
+        Ok(descriptor_entries
+            .iter()
+            .map(|indexes| indexes.iter().map(|&i| entries[i].key.clone()).collect())
+            .collect())
+    }
+
+    #[test]
+    fn replaced_configs_are_left_out_across_descriptors() {
+        let mut general = limit("path", "/", 1);
+        general["name"] = json!("general");
+        let mut vip = limit("user", "vip", 100);
+        vip["name"] = json!("vip");
+        vip["replaces"] = json!([{"name": "general"}]);
+        let domain = json!([general, vip]);
+
+        let both = request(vec![
+            descriptor(&[("path", "/")]),
+            descriptor(&[("user", "vip")]),
+        ]);
+        assert_eq!(
+            collect(domain.clone(), &both).unwrap(),
+            vec![vec![], vec!["domainuservip1002".to_owned()]]
+        );
+        let general_only = request(vec![descriptor(&[("path", "/")])]);
+        assert_eq!(
+            collect(domain, &general_only).unwrap(),
+            vec![vec!["domainpath/12".to_owned()]]
+        );
+    }
+
+    fn with_override(
+        mut descriptor: RateLimitDescriptor,
+        requests_per_unit: u32,
+        unit: i32,
+    ) -> RateLimitDescriptor {
+        descriptor.limit = Some(rate_limit_descriptor::RateLimitOverride {
+            requests_per_unit,
+            unit,
+        });
+        descriptor
+    }
+
+    #[test]
+    fn overrides_replace_the_config_and_have_their_own_counters() {
+        let domain = json!([limit("a", "1", 10)]);
+        let minutes = Unit::Minutes as i32;
+        let overridden = request(vec![with_override(descriptor(&[("a", "1")]), 2, minutes)]);
+        assert_eq!(
+            collect(domain.clone(), &overridden).unwrap(),
+            vec![vec!["domaina122_override".to_owned()]]
+        );
+
+        let steward = steward(json!({}), json!({ "domain": domain }));
+        let other = request(vec![with_override(descriptor(&[("a", "1")]), 3, minutes)]);
+        let plain = request(vec![descriptor(&[("a", "1")])]);
+        assert_eq!(
+            decide(&steward, &overridden),
+            (Code::Ok, vec![(Code::Ok, 1)])
+        );
+        assert_eq!(
+            decide(&steward, &overridden),
+            (Code::Ok, vec![(Code::Ok, 0)])
+        );
+        assert_eq!(decide(&steward, &overridden).0, Code::OverLimit);
+        assert_eq!(decide(&steward, &other), (Code::Ok, vec![(Code::Ok, 2)]));
+        assert_eq!(decide(&steward, &plain), (Code::Ok, vec![(Code::Ok, 9)]));
+        // Even an override with the same limit as the config counts on its own
+        let same = request(vec![with_override(descriptor(&[("a", "1")]), 10, minutes)]);
+        assert_eq!(decide(&steward, &same), (Code::Ok, vec![(Code::Ok, 9)]));
+    }
+
+    #[test]
+    fn overrides_are_ignored_when_disallowed() {
+        let domain = json!({"descriptors": [limit("a", "1", 10)], "disallow_overrides": true});
+        let overridden = request(vec![with_override(
+            descriptor(&[("a", "1")]),
+            2,
+            Unit::Minutes as i32,
+        )]);
+        assert_eq!(
+            collect(domain.clone(), &overridden).unwrap(),
+            vec![vec!["domaina1102".to_owned()]]
+        );
+
+        let steward = steward(json!({}), json!({ "domain": domain }));
+        let decision = steward.decide(overridden, Context::new()).unwrap();
+        let current_limit = decision.statuses[0].status.current_limit.as_ref().unwrap();
+        assert_eq!(current_limit.requests_per_unit, 10);
+    }
+
+    #[tokio::test]
+    async fn overrides_with_an_unknown_unit_are_invalid() {
+        let steward = Arc::new(steward(json!({}), json!({"domain": [limit("a", "1", 10)]})));
+        let invalid = request(vec![with_override(descriptor(&[("a", "1")]), 2, 0)]);
+        let status = steward
+            .should_rate_limit(tonic::Request::new(invalid))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    fn with_hits(mut descriptor: RateLimitDescriptor, hits: u64) -> RateLimitDescriptor {
+        descriptor.hits_addend = Some(hits);
+        descriptor
+    }
+
+    #[test]
+    fn descriptors_with_zero_hits_only_read_their_counters() {
+        let steward = steward(json!({}), json!({"domain": [limit("a", "1", 3)]}));
+        let read = request(vec![with_hits(descriptor(&[("a", "1")]), 0)]);
+        let hit = request(vec![descriptor(&[("a", "1")])]);
+        assert_eq!(decide(&steward, &read), (Code::Ok, vec![(Code::Ok, 3)]));
+        assert_eq!(decide(&steward, &read), (Code::Ok, vec![(Code::Ok, 3)]));
+        assert_eq!(decide(&steward, &hit), (Code::Ok, vec![(Code::Ok, 2)]));
+        assert_eq!(decide(&steward, &read), (Code::Ok, vec![(Code::Ok, 2)]));
+    }
+
+    #[test]
+    fn descriptor_hits_take_precedence_over_the_request() {
+        let steward = steward(json!({}), json!({"domain": [limit("a", "1", 10)]}));
+        let mut three = request(vec![descriptor(&[("a", "1")])]);
+        three.hits_addend = 3;
+        let mut two = request(vec![with_hits(descriptor(&[("a", "1")]), 2)]);
+        two.hits_addend = 3;
+        assert_eq!(decide(&steward, &three), (Code::Ok, vec![(Code::Ok, 7)]));
+        assert_eq!(decide(&steward, &two), (Code::Ok, vec![(Code::Ok, 5)]));
+    }
+
+    #[test]
+    fn zero_request_hits_are_a_query_only_when_enabled() {
+        let configs = json!({"domain": [limit("a", "1", 10)]});
+        let zero = request(vec![descriptor(&[("a", "1")])]);
+        let counting = steward(json!({}), configs.clone());
+        assert_eq!(decide(&counting, &zero), (Code::Ok, vec![(Code::Ok, 9)]));
+        let querying = steward(json!({"zero_hits_addend_is_query": true}), configs);
+        assert_eq!(decide(&querying, &zero), (Code::Ok, vec![(Code::Ok, 10)]));
+    }
+
+    #[test]
+    fn over_limit_cache_only_has_counters_that_reached_their_limit() {
+        let configs = json!({"domain": [limit("a", "1", 10)]});
+        let hits = |hits| request(vec![with_hits(descriptor(&[("a", "1")]), hits)]);
+        for check_then_commit in [false, true] {
+            let steward = steward(
+                json!({"check_then_commit": check_then_commit, "local_cache_size": 10}),
+                configs.clone(),
+            );
+            assert_eq!(decide(&steward, &hits(6)).0, Code::Ok);
+            assert_eq!(decide(&steward, &hits(5)).0, Code::OverLimit);
+            if check_then_commit {
+                // The rejected hits were never counted, so smaller requests still fit
+                assert_eq!(decide(&steward, &hits(4)), (Code::Ok, vec![(Code::Ok, 0)]));
+            }
+            assert_eq!(decide(&steward, &hits(1)).0, Code::OverLimit);
+            assert!(steward
+                .over_limit_cache
+                .as_ref()
+                .unwrap()
+                .get("domaina1102")
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn flush_syncs_pending_approximate_hits() {
+        let mut approximate = limit("a", "1", 10);
+        approximate["approximate"] = json!(true);
+        let steward = steward(
+            json!({"approximate_sync_interval_ms": 3600000}),
+            json!({ "domain": [approximate] }),
+        );
+        let a = request(vec![descriptor(&[("a", "1")])]);
+        decide(&steward, &a);
+        decide(&steward, &a);
+        let read = Counter {
+            key: "domaina1102".to_owned(),
+            interval: Some(60),
+            limit: None,
+            hits: 0,
+            approximate: false,
+        };
+        assert_eq!(steward.store().increment(&[&read]).unwrap()[0].0, 0);
+        steward.flush();
+        assert_eq!(steward.store().increment(&[&read]).unwrap()[0].0, 2);
     }
 }
@@ -0,0 +1,368 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use redis::Script;
+
+/// Increments every key by its hits, setting the expiry of new keys to their
+/// interval, and returns the incremented values along with the TTL of each key.
+/// Keys with 0 hits are only read.
+/// The hits, interval and limit of every key are passed as triples in ARGV.
+const INCREMENT_SCRIPT: &str = r"
+local rates = {}
+local ttls = {}
+for i, key in ipairs(KEYS) do
+    local hits = tonumber(ARGV[i * 3 - 2])
+    if hits > 0 then
+        rates[i] = redis.call('INCRBY', key, hits)
+        if redis.call('TTL', key) == -1 then
+            redis.call('EXPIRE', key, ARGV[i * 3 - 1])
+        end
+    else
+        rates[i] = tonumber(redis.call('GET', key) or '0')
+    end
+    ttls[i] = redis.call('TTL', key)
+end
+return {rates, ttls}
+";
+
+/// Like INCREMENT_SCRIPT, but first checks the would-be values against the
+/// limits, and only increments the keys if none of them would go past their
+/// limit. Negative limits are never over.
+/// Returns the would-be values along with the TTL of each key.
+const CHECK_THEN_INCREMENT_SCRIPT: &str = r"
+local rates = {}
+local ttls = {}
+local over = false
+for i, key in ipairs(KEYS) do
+    local limit = tonumber(ARGV[i * 3])
+    rates[i] = tonumber(redis.call('GET', key) or '0') + tonumber(ARGV[i * 3 - 2])
+    if limit >= 0 and rates[i] > limit then
+        over = true
+    end
+end
+if not over then
+    for i, key in ipairs(KEYS) do
+        local hits = tonumber(ARGV[i * 3 - 2])
+        if hits > 0 then
+            redis.call('INCRBY', key, hits)
+            if redis.call('TTL', key) == -1 then
+                redis.call('EXPIRE', key, ARGV[i * 3 - 1])
+            end
+        end
+    end
+end
+for i, key in ipairs(KEYS) do
+    ttls[i] = redis.call('TTL', key)
+end
+return {rates, ttls}
+";
+
+const LOCAL_SHARDS: usize = 16;
+
+/// A counter in the store for one of the rate limits matched by a request.
+/// Counters without a limit never prevent a check-then-commit.
+pub struct Counter {
+    pub key: String,
+    pub interval: Option<usize>,
+    pub limit: Option<i64>,
+    pub hits: u64,
+    pub approximate: bool,
+}
+
+pub trait Store: Send + Sync {
+    /// Increments every counter atomically, so that either all or none of the
+    /// counters of a request are incremented.
+    /// With check-then-commit, counters are only incremented if none of them
+    /// would go over their limit.
+    /// Rates are returned in the same order as the given counters, along with
+    /// the number of seconds until their key expires, if known.
+    fn increment(&self, counters: &[&Counter]) -> Result<Vec<(i64, Option<usize>)>, String>;
+
+    /// Returns the open and idle connections of the store, along with the
+    /// most it can open, for stores that connect to a server
+    fn pool_state(&self) -> Option<(u32, u32, u32)> {
+        None
+    }
+
+    /// Checks that the store can be reached, which stores kept in memory always can
+    fn ping(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Keeps counters in Redis, so that they are shared by every instance
+pub struct RedisStore {
+    pool: r2d2::Pool<redis::Client>,
+    script: Script,
+    default_ttl: usize,
+}
+
+impl RedisStore {
+    pub fn new(
+        redis_host: &str,
+        connections: usize,
+        timeout: Duration,
+        check_then_commit: bool,
+        default_ttl: usize,
+    ) -> Result<Self, String> {
+        let manager = redis::Client::open(format!("redis://{redis_host}"))
+            .map_err(|e| format!("Invalid redis host {redis_host}: {e}"))?;
+        // Connections are opened as they are needed, so that the service can
+        // start while Redis is down, and report it through its health checks
+        let pool = r2d2::Pool::builder()
+            .max_size(connections as u32)
+            .connection_timeout(timeout)
+            .connection_customizer(Box::new(Timeouts(timeout)))
+            .build_unchecked(manager);
+        Ok(Self {
+            pool,
+            script: match check_then_commit {
+                true => Script::new(CHECK_THEN_INCREMENT_SCRIPT),
+                false => Script::new(INCREMENT_SCRIPT),
+            },
+            default_ttl,
+        })
+    }
+}
+
+/// Bounds how long calls wait on a connection to Redis, so that they fail
+/// instead of hanging when Redis stops answering
+#[derive(Debug)]
+struct Timeouts(Duration);
+
+impl r2d2::CustomizeConnection<redis::Connection, redis::RedisError> for Timeouts {
+    fn on_acquire(&self, conn: &mut redis::Connection) -> Result<(), redis::RedisError> {
+        conn.set_read_timeout(Some(self.0))?;
+        conn.set_write_timeout(Some(self.0))
+    }
+}
+
+impl Store for RedisStore {
+    fn increment(&self, counters: &[&Counter]) -> Result<Vec<(i64, Option<usize>)>, String> {
+        let mut invocation = self.script.prepare_invoke();
+        for counter in counters.iter() {
+            invocation.key(&counter.key);
+            invocation.arg(counter.hits);
+            invocation.arg(counter.interval.unwrap_or(self.default_ttl));
+            invocation.arg(counter.limit.unwrap_or(-1));
+        }
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| format!("Failed to acquire database connection: {e}"))?;
+        let (rates, ttls) = invocation
+            .invoke::<(Vec<i64>, Vec<i64>)>(&mut *conn)
+            .map_err(|e| format!("Failed to increment entries in db: {e}"))?;
+        let ttls = ttls.into_iter().map(|ttl| usize::try_from(ttl).ok());
+        Ok(rates.into_iter().zip(ttls).collect())
+    }
+
+    fn ping(&self) -> Result<(), String> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| format!("Failed to acquire database connection: {e}"))?;
+        redis::cmd("PING")
+            .query::<String>(&mut *conn)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to ping db: {e}"))
+    }
+
+    fn pool_state(&self) -> Option<(u32, u32, u32)> {
+        let state = self.pool.state();
+        Some((
+            state.connections,
+            state.idle_connections,
+            self.pool.max_size(),
+        ))
+    }
+}
+
+/// Keeps counters in memory, for instances that only enforce their own limits.
+/// Counters are spread over shards by key, so that requests for different keys
+/// rarely wait on each other, and expire the same way as they do in Redis.
+pub struct LocalStore {
+    shards: Vec<Mutex<HashMap<String, (i64, Instant)>>>,
+    check_then_commit: bool,
+    default_ttl: usize,
+}
+
+impl LocalStore {
+    pub fn new(check_then_commit: bool, default_ttl: usize) -> Self {
+        Self {
+            shards: (0..LOCAL_SHARDS).map(|_| Mutex::default()).collect(),
+            check_then_commit,
+            default_ttl,
+        }
+    }
+
+    /// Drops every counter whose window has ended
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().retain(|_, (_, expiry)| *expiry > now);
+        }
+    }
+
+    fn shard(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+}
+
+impl Store for LocalStore {
+    fn increment(&self, counters: &[&Counter]) -> Result<Vec<(i64, Option<usize>)>, String> {
+        let shards: Vec<_> = counters.iter().map(|c| self.shard(&c.key)).collect();
+        // Shards are always locked in the same order, so that requests sharing
+        // some of them cannot deadlock
+        let mut order = shards.clone();
+        order.sort_unstable();
+        order.dedup();
+        let mut guards: HashMap<usize, MutexGuard<_>> = order
+            .into_iter()
+            .map(|i| (i, self.shards[i].lock().unwrap()))
+            .collect();
+
+        let now = Instant::now();
+        let rates: Vec<i64> = counters
+            .iter()
+            .zip(shards.iter())
+            .map(|(counter, shard)| {
+                let current = match guards[shard].get(&counter.key) {
+                    Some((rate, expiry)) if *expiry > now => *rate,
+                    _ => 0,
+                };
+                current + counter.hits as i64
+            })
+            .collect();
+        let over = self.check_then_commit
+            && counters
+                .iter()
+                .zip(rates.iter())
+                .any(|(counter, rate)| counter.limit.is_some_and(|l| l >= 0 && *rate > l));
+
+        let mut results = Vec::with_capacity(counters.len());
+        for ((counter, shard), rate) in counters.iter().zip(shards.iter()).zip(rates) {
+            let keys = guards.get_mut(shard).unwrap();
+            if counter.hits > 0 && !over {
+                let interval = counter.interval.unwrap_or(self.default_ttl) as u64;
+                let value = keys
+                    .entry(counter.key.to_owned())
+                    .or_insert((0, now + Duration::from_secs(interval)));
+                if value.1 <= now {
+                    *value = (0, now + Duration::from_secs(interval));
+                }
+                value.0 += counter.hits as i64;
+            }
+            let ttl = keys
+                .get(&counter.key)
+                .filter(|(_, expiry)| *expiry > now)
+                .map(|(_, expiry)| (*expiry - now).as_secs_f64().round() as usize);
+            results.push((rate, ttl));
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn counter(key: &str, hits: u64, interval: usize, limit: Option<i64>) -> Counter {
+        Counter {
+            key: key.to_owned(),
+            interval: Some(interval),
+            limit,
+            hits,
+            approximate: false,
+        }
+    }
+
+    fn rates(store: &LocalStore, counters: &[&Counter]) -> Vec<i64> {
+        let results = store.increment(counters).unwrap();
+        results.into_iter().map(|(rate, _)| rate).collect()
+    }
+
+    #[test]
+    fn local_store_increments_every_counter() {
+        let store = LocalStore::new(false, 60);
+        let (a, b) = (counter("a", 2, 60, None), counter("b", 3, 60, None));
+        assert_eq!(rates(&store, &[&a, &b]), vec![2, 3]);
+        assert_eq!(rates(&store, &[&a, &b]), vec![4, 6]);
+        assert_eq!(store.increment(&[&a]).unwrap(), vec![(6, Some(60))]);
+    }
+
+    #[test]
+    fn local_store_reads_counters_with_zero_hits() {
+        let store = LocalStore::new(false, 60);
+        let (hit, read) = (counter("a", 1, 60, None), counter("a", 0, 60, None));
+        assert_eq!(store.increment(&[&read]).unwrap(), vec![(0, None)]);
+        assert_eq!(rates(&store, &[&hit]), vec![1]);
+        assert_eq!(store.increment(&[&read]).unwrap(), vec![(1, Some(60))]);
+        assert_eq!(rates(&store, &[&read]), vec![1]);
+    }
+
+    #[test]
+    fn local_store_expires_counters_after_their_interval() {
+        let store = LocalStore::new(false, 60);
+        let (short, long) = (counter("a", 1, 1, None), counter("b", 1, 60, None));
+        assert_eq!(rates(&store, &[&short, &long]), vec![1, 1]);
+        assert_eq!(rates(&store, &[&short, &long]), vec![2, 2]);
+        sleep(Duration::from_millis(1100));
+        assert_eq!(rates(&store, &[&short, &long]), vec![1, 3]);
+
+        sleep(Duration::from_millis(1100));
+        store.sweep();
+        let counters: usize = store.shards.iter().map(|s| s.lock().unwrap().len()).sum();
+        assert_eq!(counters, 1);
+    }
+
+    #[test]
+    fn local_store_uses_default_ttl_without_interval() {
+        let store = LocalStore::new(false, 30);
+        let mut a = counter("a", 1, 0, None);
+        a.interval = None;
+        assert_eq!(store.increment(&[&a]).unwrap(), vec![(1, Some(30))]);
+    }
+
+    #[test]
+    fn local_store_check_then_commit_increments_nothing_when_any_counter_would_be_over() {
+        let store = LocalStore::new(true, 60);
+        let tight = counter("tight", 1, 60, Some(2));
+        let loose = counter("loose", 1, 60, Some(10));
+        let unlimited = counter("unlimited", 1, 60, None);
+        let counters = [&tight, &loose, &unlimited];
+        assert_eq!(rates(&store, &counters), vec![1, 1, 1]);
+        assert_eq!(rates(&store, &counters), vec![2, 2, 2]);
+        // Returns the would-be rates, without committing them
+        assert_eq!(rates(&store, &counters), vec![3, 3, 3]);
+        assert_eq!(rates(&store, &counters), vec![3, 3, 3]);
+
+        let reads: Vec<_> = counters
+            .iter()
+            .map(|c| counter(&c.key, 0, 60, c.limit))
+            .collect();
+        assert_eq!(
+            rates(&store, &reads.iter().collect::<Vec<_>>()),
+            vec![2, 2, 2]
+        );
+        // Counters without a limit, or with a negative one, never prevent a commit
+        let negative = counter("tight", 1, 60, Some(-1));
+        assert_eq!(rates(&store, &[&negative, &unlimited]), vec![3, 3]);
+    }
+
+    #[test]
+    fn local_store_without_check_then_commit_increments_past_the_limit() {
+        let store = LocalStore::new(false, 60);
+        let a = counter("a", 1, 60, Some(1));
+        assert_eq!(rates(&store, &[&a]), vec![1]);
+        assert_eq!(rates(&store, &[&a]), vec![2]);
+        assert_eq!(rates(&store, &[&a]), vec![3]);
+    }
+}
@@ -0,0 +1,95 @@
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::{TextMapCompositePropagator, TraceContextPropagator};
+use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use tonic::metadata::{KeyRef, MetadataMap};
+use tracing::error;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+use crate::config_source::TracingSettings;
+
+/// Sets up JSON logs, and exports spans over OTLP when tracing is configured.
+/// Trace context is propagated in either the W3C `traceparent` or B3 headers.
+pub fn init(settings: Option<&TracingSettings>) {
+    global::set_text_map_propagator(TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(opentelemetry_zipkin::Propagator::new()),
+    ]));
+
+    let logs = tracing_subscriber::fmt::layer()
+        .json()
+        .with_file(true)
+        .with_line_number(true)
+        .with_filter(EnvFilter::from_default_env());
+    let (spans, tracer_error) = match settings.map(otlp_tracer) {
+        Some(Ok(tracer)) => (
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(LevelFilter::INFO),
+            ),
+            None,
+        ),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+    tracing_subscriber::registry().with(logs).with(spans).init();
+
+    if let Some(e) = tracer_error {
+        error!("Could not set up trace exporting: {e}");
+    }
+}
+
+/// Flushes every span that has not been exported yet
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+fn otlp_tracer(settings: &TracingSettings) -> Result<Tracer, String> {
+    let service_name = settings.service_name.as_deref().unwrap_or("steward");
+    let sampler = Sampler::TraceIdRatioBased(settings.sample_ratio.unwrap_or(1.0));
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&settings.otlp_endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(Sampler::ParentBased(Box::new(sampler)))
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    service_name.to_owned(),
+                )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+        .map_err(|e| e.to_string())
+}
+
+/// Reads the trace context that Envoy sent along with a request
+pub fn extract_context(metadata: &MetadataMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&MetadataExtractor(metadata)))
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(|key| match key {
+                KeyRef::Ascii(key) => key.as_str(),
+                KeyRef::Binary(key) => key.as_str(),
+            })
+            .collect()
+    }
+}
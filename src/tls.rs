@@ -0,0 +1,242 @@
+use std::fs;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore,
+    ServerConfig, Session,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, info, warn};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+use crate::config_source::TlsSettings;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Incoming = ReceiverStream<io::Result<TlsStream<TcpStream>>>;
+
+/// Accepts TLS connections on the gRPC listener, with the certificates last
+/// loaded from disk. Certificates are reloaded whenever their files change.
+pub fn incoming(listener: TcpListener, settings: TlsSettings) -> Result<Incoming, String> {
+    let san_patterns = settings.client_san_patterns.clone().unwrap_or_default();
+    if !san_patterns.is_empty() && settings.client_ca_path.is_none() {
+        return Err("client_san_patterns requires a client_ca_path".into());
+    }
+    let config = Arc::new(RwLock::new(Arc::new(load_config(&settings)?)));
+    info!(
+        "Serving gRPC over TLS with the certificate in {}",
+        settings.cert_path
+    );
+
+    let interval = Duration::from_millis(settings.reload_interval_ms.unwrap_or(10000));
+    tokio::spawn(reload(config.clone(), settings, interval));
+    let (tx, rx) = mpsc::channel(128);
+    tokio::spawn(accept(listener, config, Arc::new(san_patterns), tx));
+    Ok(ReceiverStream::new(rx))
+}
+
+/// Hands connections over once their handshake is done, until the server
+/// stops taking them. Handshakes run on their own tasks, so that slow clients
+/// do not hold up the others.
+async fn accept(
+    listener: TcpListener,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+    san_patterns: Arc<Vec<String>>,
+    tx: Sender<io::Result<TlsStream<TcpStream>>>,
+) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept connection: {e}");
+                    sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = tx.closed() => return,
+        };
+        let acceptor = TlsAcceptor::from(config.read().unwrap().clone());
+        let (tx, san_patterns) = (tx.clone(), san_patterns.clone());
+        tokio::spawn(async move {
+            match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) if client_allowed(&stream, &san_patterns) => {
+                    let _ = tx.send(Ok(stream)).await;
+                }
+                Ok(Ok(_)) => warn!("Rejected client {peer}, no SAN matches client_san_patterns"),
+                Ok(Err(e)) => warn!("TLS handshake with {peer} failed: {e}"),
+                Err(_) => warn!("TLS handshake with {peer} timed out"),
+            }
+        });
+    }
+}
+
+/// Swaps in the certificates from disk whenever their files have changed.
+/// Connections that are already open keep the certificates they started with.
+async fn reload(config: Arc<RwLock<Arc<ServerConfig>>>, settings: TlsSettings, interval: Duration) {
+    let mut modified = modified_at(&settings);
+    loop {
+        sleep(interval).await;
+        let latest = modified_at(&settings);
+        if latest == modified {
+            continue;
+        }
+        match load_config(&settings) {
+            Ok(loaded) => {
+                *config.write().unwrap() = Arc::new(loaded);
+                modified = latest;
+                info!("Reloaded TLS certificates");
+            }
+            Err(e) => error!("Failed to reload TLS certificates, keeping the previous ones: {e}"),
+        }
+    }
+}
+
+fn modified_at(settings: &TlsSettings) -> Vec<Option<SystemTime>> {
+    [
+        Some(&settings.cert_path),
+        Some(&settings.key_path),
+        settings.client_ca_path.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+fn load_config(settings: &TlsSettings) -> Result<ServerConfig, String> {
+    let certs = load_certs(&settings.cert_path)?;
+    let key = load_key(&settings.key_path)?;
+    let verifier = match &settings.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| format!("Invalid client CA in {path}: {e}"))?;
+            }
+            AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate or key: {e}"))?;
+    config.set_protocols(&[b"h2".to_vec()]);
+    Ok(config)
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let pem = fs::read(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+    match pemfile::certs(&mut pem.as_slice()) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(format!("No certificates found in {path}")),
+    }
+}
+
+/// Reads the first PKCS#8 or RSA private key
+fn load_key(path: &str) -> Result<PrivateKey, String> {
+    let pem = fs::read(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut pem.as_slice()).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut pem.as_slice()).unwrap_or_default();
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| format!("No private key found in {path}"))
+}
+
+/// Checks that one of the DNS, URI or email SANs of the client certificate
+/// matches one of the patterns. Any client is allowed without patterns.
+fn client_allowed(stream: &TlsStream<TcpStream>, san_patterns: &[String]) -> bool {
+    if san_patterns.is_empty() {
+        return true;
+    }
+    let certs = stream
+        .get_ref()
+        .1
+        .get_peer_certificates()
+        .unwrap_or_default();
+    let Some(Ok((_, cert))) = certs.first().map(|cert| parse_x509_certificate(&cert.0)) else {
+        return false;
+    };
+    let Ok(Some(sans)) = cert.subject_alternative_name() else {
+        return false;
+    };
+    sans.value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(san) | GeneralName::URI(san) | GeneralName::RFC822Name(san) => {
+                Some(san)
+            }
+            _ => None,
+        })
+        .any(|san| san_patterns.iter().any(|pattern| matches(pattern, san)))
+}
+
+/// Matches a SAN against a pattern, segment by segment, where `*` stands
+/// for any run of characters within a single `/`-separated segment
+fn matches(pattern: &str, san: &str) -> bool {
+    let patterns: Vec<_> = pattern.split('/').collect();
+    let segments: Vec<_> = san.split('/').collect();
+    patterns.len() == segments.len()
+        && patterns
+            .iter()
+            .zip(segments)
+            .all(|(pattern, segment)| matches_segment(pattern, segment))
+}
+
+fn matches_segment(pattern: &str, segment: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = segment.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_within_a_segment() {
+        let pattern = "spiffe://cluster.local/ns/*/sa/envoy";
+        assert!(matches(
+            pattern,
+            "spiffe://cluster.local/ns/ingress/sa/envoy"
+        ));
+        assert!(!matches(pattern, "spiffe://cluster.local/ns/a/b/sa/envoy"));
+        assert!(!matches(pattern, "spiffe://cluster.local/ns//sa/envoy/x"));
+        assert!(!matches(
+            pattern,
+            "spiffe://cluster.local/ns/ingress/sa/other"
+        ));
+
+        assert!(matches("envoy-*.mesh.local", "envoy-1.mesh.local"));
+        assert!(!matches("envoy-*.mesh.local", "envoy-1.mesh.local.evil"));
+        assert!(matches("*-envoy-*", "ingress-envoy-1"));
+        assert!(matches("envoy", "envoy"));
+        assert!(!matches("envoy", "envoy2"));
+    }
+}